@@ -1,5 +1,4 @@
 #!/usr/bin/env bash
 
-killall swhks
-
-swhks & pkexec swhkdp
+# swhks is started by systemd through swhks.socket on the first command.
+pkexec swhkdp
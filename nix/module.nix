@@ -7,9 +7,16 @@ self: {
   cfg = config.services.swhkdp;
   inherit (pkgs.stdenv.hostPlatform) system;
   inherit (lib) types;
-  inherit (lib.modules) mkIf;
+  inherit (lib.modules) mkIf mkRemovedOptionModule;
   inherit (lib.options) mkOption mkEnableOption;
 in {
+  imports = [
+    (mkRemovedOptionModule ["services" "swhkdp" "cooldown"] ''
+      The repeat cooldown is now configured in swhkdprc: set `repeat_rate` (and `repeat_delay`)
+      in the `general { }` block, e.g. through `services.swhkdp.settings`.
+    '')
+  ];
+
   options.services.swhkdp = {
     enable = mkEnableOption "Simple Wayland HotKey Daemon Polkit";
 
@@ -25,12 +32,6 @@ in {
       type = types.package;
     };
 
-    cooldown = mkOption {
-      description = "The cooldown to use for `swhkdp`";
-      default = 250;
-      type = types.int;
-    };
-
     devices = mkOption {
       description = "The list of devices to use for `swhkdp`";
       default = [];
@@ -83,7 +84,6 @@ in {
           else "";
       in ''
         /run/wrappers/bin/pkexec ${cfg.package}/bin/swhkdp ${devicesCmd}\
-          --cooldown ${toString cfg.cooldown} \
           -I "${lib.concatStringsSep "|" cfg.ignore}"
       '';
       serviceConfig.Restart = "always";
@@ -1,4 +1,6 @@
-use evdev::KeyCode;
+#[cfg(feature = "macro")]
+use crate::layout::{Layout, TypedKey};
+use evdev::{KeyCode, SwitchCode};
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::str::FromStr;
@@ -15,6 +17,8 @@ pub struct Mode {
     pub hotkeys: Vec<Hotkey>,
     pub remaps: HashMap<KeyCode, KeyCode>,
     pub unbinds: Vec<KeyBinding>,
+    pub axis_hotkeys: Vec<AxisHotkey>,
+    pub switch_hotkeys: Vec<SwitchHotkey>,
     pub options: ModeOptions,
 }
 
@@ -22,6 +26,129 @@ pub struct Mode {
 pub struct Hotkey {
     pub keybind: KeyBinding,
     pub action: HotkeyAction,
+    pub repeat: RepeatOptions,
+    /// Canonical name of what fires the hotkey, e.g. `KEY_LEFTMETA+KEY_A` or `SW_LID`.
+    pub trigger: String,
+    pub command: CommandOptions,
+}
+
+/// What to do when a hotkey fires while the command it started last time is still running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Instance {
+    /// Start another one.
+    #[default]
+    Multiple,
+    /// Leave the running one alone and start nothing.
+    Single,
+    /// Stop the running one, or start one if none is running.
+    Toggle,
+}
+
+/// How swhks runs the shell command of a hotkey.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandOptions {
+    pub instance: Instance,
+    /// Milliseconds after which swhks terminates the command's process group.
+    pub timeout: Option<u32>,
+    /// Extra environment variables, from the hotkey's `env` block.
+    pub env: Vec<(String, String)>,
+    /// Working directory, swhks expands a leading `~`.
+    pub cwd: Option<String>,
+}
+
+/// Modifiers sorted by code so the same binding always gets the same name.
+pub fn trigger_name(modifiers: &HashSet<KeyCode>, last: &str) -> String {
+    let mut modifiers = modifiers.iter().collect::<Vec<_>>();
+    modifiers.sort_by_key(|m| m.code());
+    modifiers.iter().map(|m| format!("{m:?}+")).chain([last.to_string()]).collect()
+}
+
+/// Scroll direction usable as the last element of a keycodes line, e.g. `KEY_LEFTMETA+REL_WHEEL_UP`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WheelDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl FromStr for WheelDirection {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "REL_WHEEL_UP" => Ok(WheelDirection::Up),
+            "REL_WHEEL_DOWN" => Ok(WheelDirection::Down),
+            "REL_HWHEEL_LEFT" => Ok(WheelDirection::Left),
+            "REL_HWHEEL_RIGHT" => Ok(WheelDirection::Right),
+            _ => Err(()),
+        }
+    }
+}
+
+impl WheelDirection {
+    pub fn name(self) -> &'static str {
+        match self {
+            WheelDirection::Up => "REL_WHEEL_UP",
+            WheelDirection::Down => "REL_WHEEL_DOWN",
+            WheelDirection::Left => "REL_HWHEEL_LEFT",
+            WheelDirection::Right => "REL_HWHEEL_RIGHT",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AxisHotkey {
+    pub direction: WheelDirection,
+    pub modifiers: HashSet<KeyCode>,
+    pub action: HotkeyAction,
+    pub command: CommandOptions,
+}
+
+impl AxisHotkey {
+    /// Wheel bindings have no keysym, `KEY_RESERVED` stands in for it so they can share the
+    /// key dispatch path. They never repeat, every wheel detent dispatches once.
+    pub fn to_hotkey(&self) -> Hotkey {
+        Hotkey {
+            keybind: KeyBinding {
+                keysym: KeyCode::KEY_RESERVED,
+                modifiers: self.modifiers.clone(),
+                send: false,
+                on_release: false,
+            },
+            action: self.action.clone(),
+            repeat: RepeatOptions { enabled: false, ..RepeatOptions::default() },
+            trigger: trigger_name(&self.modifiers, self.direction.name()),
+            command: self.command.clone(),
+        }
+    }
+}
+
+/// Fires when `switch` changes to `state`, e.g. `SW_LID "on" "systemctl suspend"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchHotkey {
+    pub switch: SwitchCode,
+    pub state: bool,
+    pub action: HotkeyAction,
+    pub command: CommandOptions,
+}
+
+impl SwitchHotkey {
+    /// Same stand-in as [`AxisHotkey::to_hotkey`], switches have neither keysym nor modifiers.
+    pub fn to_hotkey(&self) -> Hotkey {
+        Hotkey {
+            keybind: KeyBinding {
+                keysym: KeyCode::KEY_RESERVED,
+                modifiers: HashSet::new(),
+                send: false,
+                on_release: false,
+            },
+            action: self.action.clone(),
+            repeat: RepeatOptions { enabled: false, ..RepeatOptions::default() },
+            trigger: format!("{:?}", self.switch),
+            command: self.command.clone(),
+        }
+    }
 }
 
 impl Value for &Hotkey {
@@ -73,6 +200,24 @@ pub struct KeyBinding {
 pub struct ModeOptions {
     pub swallow: bool,
     pub oneoff: bool,
+    /// Program and leading arguments that shell commands are appended to, e.g. `sh -c`.
+    pub shell: Vec<String>,
+}
+
+/// Key-repeat behavior while a hotkey is held. Delays are in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepeatOptions {
+    pub enabled: bool,
+    /// Time between the initial dispatch and the first repeat.
+    pub delay: u64,
+    /// Time between subsequent repeats.
+    pub rate: u64,
+}
+
+impl Default for RepeatOptions {
+    fn default() -> Self {
+        RepeatOptions { enabled: true, delay: 250, rate: 250 }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -87,6 +232,20 @@ pub enum HotkeyAction {
 pub struct MacroDef {
     pub macro_type: MacroType,
     pub steps: Vec<MacroStep>,
+    /// Milliseconds between press and release of every emitted click.
+    pub key_delay: u32,
+    /// Physical key that stops the macro while it runs, `KEY_ESC` by default for endless macros.
+    pub stop_key: Option<KeyCode>,
+    /// What happens when the trigger fires again while the macro is still running.
+    pub policy: MacroPolicy,
+    /// Whether other hotkeys still fire while the macro runs.
+    pub allow_hotkeys: bool,
+    /// Whether unbound keys still reach the virtual keyboard while the macro runs.
+    pub allow_passthrough: bool,
+    /// Divides every duration in the macro, 2.0 plays it twice as fast.
+    pub speed: f64,
+    /// Milliseconds between the events of `move` and `scroll` steps.
+    pub step_interval: u32,
 }
 
 #[cfg(feature = "macro")]
@@ -95,14 +254,37 @@ pub enum MacroType {
     Simple,
     Endless,
     Hold,
+    Toggle,
+}
+
+#[cfg(feature = "macro")]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MacroPolicy {
+    /// Stop the running instance and start over.
+    #[default]
+    Replace,
+    /// Keep the running instance and drop the new trigger.
+    Ignore,
+    /// Start the new instance once the running one finishes.
+    Queue,
+    /// Run the new instance alongside the running one.
+    Parallel,
 }
 
+/// One step of a macro. `Scroll` is in wheel detents, `y` follows REL_WHEEL (positive scrolls
+/// up) and `x` REL_HWHEEL.
 #[cfg(feature = "macro")]
 #[derive(Debug, Clone, PartialEq)]
 pub enum MacroStep {
     KeyAction { key: KeyCode, action: KeyAction },
     Move { x: i32, y: i32, duration: u32, move_type: MoveType, path: MovePath },
     Repeat { count: u32, steps: Vec<MacroStep> },
+    Type { keys: Vec<TypedKey>, delay: u32 },
+    Wait { duration: u32, jitter: u32 },
+    Scroll { x: i32, y: i32, duration: u32, move_type: MoveType },
+    WaitKey { key: KeyCode, down: bool, timeout: Option<u32> },
+    Exec(String),
+    Enter(String),
 }
 
 #[cfg(feature = "macro")]
@@ -119,8 +301,20 @@ pub enum MoveType {
     Constant,
     Accelerate,
     Decelerate,
+    EaseInOut,
+    /// CSS-style `cubic-bezier(x1, y1, x2, y2)`, the curve runs from (0, 0) to (1, 1).
+    Bezier {
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+    },
 }
 
+/// Milliseconds between the events of a `move` or `scroll` step unless `step_interval=` says otherwise.
+#[cfg(feature = "macro")]
+pub const DEFAULT_STEP_INTERVAL_MS: u32 = 8;
+
 #[cfg(feature = "macro")]
 #[derive(Debug, Clone, PartialEq)]
 pub enum MovePath {
@@ -132,11 +326,26 @@ struct GeneralSettings {
     default_mode: String,
     oneoff: bool,
     swallow: bool,
+    repeat: RepeatOptions,
+    shell: Vec<String>,
+    #[cfg(feature = "macro")]
+    layout: Layout,
+    #[cfg(feature = "macro")]
+    macros: HashMap<String, MacroDef>,
 }
 
 fn parse_general(doc: &kdl::KdlDocument) -> GeneralSettings {
-    let mut settings =
-        GeneralSettings { default_mode: "master".to_string(), oneoff: false, swallow: false };
+    let mut settings = GeneralSettings {
+        default_mode: "master".to_string(),
+        oneoff: false,
+        swallow: false,
+        repeat: RepeatOptions::default(),
+        shell: vec!["sh".to_string(), "-c".to_string()],
+        #[cfg(feature = "macro")]
+        layout: crate::layout::us_qwerty(),
+        #[cfg(feature = "macro")]
+        macros: HashMap::new(),
+    };
 
     let general_node = match doc.get("general") {
         Some(node) => node,
@@ -178,6 +387,47 @@ fn parse_general(doc: &kdl::KdlDocument) -> GeneralSettings {
                     }
                 }
             }
+            "repeat" => {
+                if let Some(val) = node.get(0) {
+                    if let Some(b) = val.as_bool() {
+                        settings.repeat.enabled = b;
+                    } else {
+                        log::warn!("general.repeat value must be a boolean");
+                    }
+                }
+            }
+            "repeat_delay" => {
+                if let Some(val) = node.get(0) {
+                    match val.as_integer().and_then(|n| u64::try_from(n).ok()) {
+                        Some(n) => settings.repeat.delay = n,
+                        None => log::warn!("general.repeat_delay value must be an integer >= 0"),
+                    }
+                }
+            }
+            "repeat_rate" => {
+                if let Some(val) = node.get(0) {
+                    match val.as_integer().and_then(|n| u64::try_from(n).ok()) {
+                        Some(n) if n > 0 => settings.repeat.rate = n,
+                        _ => log::warn!("general.repeat_rate value must be an integer > 0"),
+                    }
+                }
+            }
+            "shell" => {
+                let args: Option<Vec<_>> =
+                    node.entries().iter().map(|e| e.value().as_string()).collect();
+                match args {
+                    Some(args) if !args.is_empty() => {
+                        settings.shell = args.into_iter().map(str::to_string).collect()
+                    }
+                    _ => log::warn!("general.shell must be one or more strings"),
+                }
+            }
+            #[cfg(feature = "macro")]
+            "layout" => {
+                if let Some(entries) = node.children() {
+                    parse_layout(entries, &mut settings.layout);
+                }
+            }
             _ => {
                 log::warn!("Unknown general setting: {name}");
             }
@@ -187,60 +437,174 @@ fn parse_general(doc: &kdl::KdlDocument) -> GeneralSettings {
     settings
 }
 
+// Each entry maps one character to a key, e.g. `"z" KEY_Y` or `"Z" KEY_Y shift=#true`.
+#[cfg(feature = "macro")]
+fn parse_layout(doc: &kdl::KdlDocument, layout: &mut Layout) {
+    for node in doc.nodes() {
+        let name = node.name().value();
+        let mut chars = name.chars();
+        let c = match (chars.next(), chars.next()) {
+            (Some(c), None) => c,
+            _ => {
+                log::warn!("general.layout entry must be a single character, got {name:?}");
+                continue;
+            }
+        };
+        let key = match node.get(0).and_then(|v| v.as_string()).map(KeyCode::from_str) {
+            Some(Ok(key)) => key,
+            _ => {
+                log::warn!("general.layout entry {name:?} must map to a key name");
+                continue;
+            }
+        };
+        let shift = node.get("shift").and_then(|v| v.as_bool()).unwrap_or(false);
+        layout.insert(c, TypedKey { key, shift });
+    }
+}
+
+fn parse_repeat(node: &kdl::KdlNode, default: RepeatOptions, keycodes_raw: &str) -> RepeatOptions {
+    let mut repeat = default;
+    if let Some(val) = node.get("repeat") {
+        match val.as_bool() {
+            Some(b) => repeat.enabled = b,
+            None => log::warn!("repeat must be a boolean for {keycodes_raw:?}; using default"),
+        }
+    }
+    if let Some(val) = node.get("repeat_delay") {
+        match val.as_integer().and_then(|n| u64::try_from(n).ok()) {
+            Some(n) => repeat.delay = n,
+            None => log::warn!("repeat_delay must be an integer >= 0 for {keycodes_raw:?}"),
+        }
+    }
+    if let Some(val) = node.get("repeat_rate") {
+        match val.as_integer().and_then(|n| u64::try_from(n).ok()) {
+            Some(n) if n > 0 => repeat.rate = n,
+            _ => log::warn!("repeat_rate must be an integer > 0 for {keycodes_raw:?}"),
+        }
+    }
+    repeat
+}
+
+fn parse_command_options(node: &kdl::KdlNode, keycodes_raw: &str) -> CommandOptions {
+    let mut options = CommandOptions::default();
+    if let Some(val) = node.get("instance") {
+        match val.as_string() {
+            Some("multiple") => options.instance = Instance::Multiple,
+            Some("single") => options.instance = Instance::Single,
+            Some("toggle") => options.instance = Instance::Toggle,
+            _ => log::warn!(
+                "instance must be \"multiple\", \"single\" or \"toggle\" for {keycodes_raw:?}"
+            ),
+        }
+    }
+    if let Some(val) = node.get("timeout") {
+        match val.as_integer().and_then(|n| u32::try_from(n).ok()) {
+            Some(n) if n > 0 => options.timeout = Some(n),
+            _ => log::warn!("timeout must be an integer > 0 for {keycodes_raw:?}"),
+        }
+    }
+    if let Some(val) = node.get("cwd") {
+        match val.as_string() {
+            Some(cwd) if !cwd.is_empty() => options.cwd = Some(cwd.to_string()),
+            _ => log::warn!("cwd must be a non-empty string for {keycodes_raw:?}"),
+        }
+    }
+    let env_nodes =
+        node.children().into_iter().flat_map(|c| c.nodes()).filter(|n| n.name().value() == "env");
+    for var in env_nodes.flat_map(|n| n.children()).flat_map(|c| c.nodes()) {
+        let name = var.name().value();
+        match var.get(0).and_then(|v| v.as_string()) {
+            Some(value) => options.env.push((name.to_string(), value.to_string())),
+            None => log::warn!("env value of {name} must be a string for {keycodes_raw:?}"),
+        }
+    }
+    options
+}
+
 fn action_has_empty_segment(action: &str) -> bool {
     action.contains('@') && action.split("&&").map(str::trim).any(str::is_empty)
 }
 
+// x, y, duration and type are shared by the `move` and `scroll` steps.
 #[cfg(feature = "macro")]
-fn parse_macro_steps(doc: &kdl::KdlDocument) -> Vec<MacroStep> {
+fn parse_motion(node: &kdl::KdlNode, step: &str) -> (i32, i32, u32, MoveType) {
+    let x = match node.get("x") {
+        None => 0i32,
+        Some(v) => match v.as_integer() {
+            Some(n) => n as i32,
+            None => {
+                log::warn!("{step} x must be an integer; defaulting to 0");
+                0
+            }
+        },
+    };
+    let y = match node.get("y") {
+        None => 0i32,
+        Some(v) => match v.as_integer() {
+            Some(n) => n as i32,
+            None => {
+                log::warn!("{step} y must be an integer; defaulting to 0");
+                0
+            }
+        },
+    };
+    let duration = match node.get("duration") {
+        None => 0u32,
+        Some(v) => match v.as_integer() {
+            Some(n) if n >= 0 => n as u32,
+            Some(n) => {
+                log::warn!("{step} duration must be >= 0, got {n}; defaulting to 0");
+                0
+            }
+            None => {
+                log::warn!("{step} duration must be an integer; defaulting to 0");
+                0
+            }
+        },
+    };
+    let move_type = match node.get("type").and_then(|v| v.as_string()) {
+        None | Some("constant") => MoveType::Constant,
+        Some("accelerate") => MoveType::Accelerate,
+        Some("decelerate") => MoveType::Decelerate,
+        Some("ease-in-out") => MoveType::EaseInOut,
+        Some(other) => match parse_bezier(other) {
+            Some(bezier) => bezier,
+            None => {
+                log::warn!("Unknown {step} type {other:?}; defaulting to \"constant\"");
+                MoveType::Constant
+            }
+        },
+    };
+    (x, y, duration, move_type)
+}
+
+// `cubic-bezier(x1, y1, x2, y2)`, x values must stay within 0..=1 so time only moves forward.
+#[cfg(feature = "macro")]
+fn parse_bezier(value: &str) -> Option<MoveType> {
+    let args = value.strip_prefix("cubic-bezier(")?.strip_suffix(')')?;
+    let points =
+        args.split(',').map(|p| p.trim().parse::<f64>().ok()).collect::<Option<Vec<_>>>()?;
+    let [x1, y1, x2, y2] = points[..] else {
+        return None;
+    };
+    if !(0.0..=1.0).contains(&x1)
+        || !(0.0..=1.0).contains(&x2)
+        || !y1.is_finite()
+        || !y2.is_finite()
+    {
+        return None;
+    }
+    Some(MoveType::Bezier { x1, y1, x2, y2 })
+}
+
+#[cfg(feature = "macro")]
+fn parse_macro_steps(doc: &kdl::KdlDocument, general: &GeneralSettings) -> Vec<MacroStep> {
     let mut steps = vec![];
     for node in doc.nodes() {
         let name = node.name().value();
         match name {
             "move" => {
-                let x = match node.get("x") {
-                    None => 0i32,
-                    Some(v) => match v.as_integer() {
-                        Some(n) => n as i32,
-                        None => {
-                            log::warn!("move x must be an integer; defaulting to 0");
-                            0
-                        }
-                    },
-                };
-                let y = match node.get("y") {
-                    None => 0i32,
-                    Some(v) => match v.as_integer() {
-                        Some(n) => n as i32,
-                        None => {
-                            log::warn!("move y must be an integer; defaulting to 0");
-                            0
-                        }
-                    },
-                };
-                let duration = match node.get("duration") {
-                    None => 0u32,
-                    Some(v) => match v.as_integer() {
-                        Some(n) if n >= 0 => n as u32,
-                        Some(n) => {
-                            log::warn!("move duration must be >= 0, got {n}; defaulting to 0");
-                            0
-                        }
-                        None => {
-                            log::warn!("move duration must be an integer; defaulting to 0");
-                            0
-                        }
-                    },
-                };
-                let move_type = match node.get("type").and_then(|v| v.as_string()) {
-                    None | Some("constant") => MoveType::Constant,
-                    Some("accelerate") => MoveType::Accelerate,
-                    Some("decelerate") => MoveType::Decelerate,
-                    Some(other) => {
-                        log::warn!("Unknown move type {other:?}; defaulting to \"constant\"");
-                        MoveType::Constant
-                    }
-                };
+                let (x, y, duration, move_type) = parse_motion(node, "move");
                 let path = match node.get("path").and_then(|v| v.as_string()) {
                     None | Some("direct") => MovePath::Direct,
                     Some("arc") => {
@@ -261,6 +625,117 @@ fn parse_macro_steps(doc: &kdl::KdlDocument) -> Vec<MacroStep> {
                 };
                 steps.push(MacroStep::Move { x, y, duration, move_type, path });
             }
+            "scroll" => {
+                let (x, y, duration, move_type) = parse_motion(node, "scroll");
+                steps.push(MacroStep::Scroll { x, y, duration, move_type });
+            }
+            "type" => {
+                let text = match node.get(0).and_then(|v| v.as_string()) {
+                    Some(text) => text,
+                    None => {
+                        log::warn!("type step requires a string argument; skipping");
+                        continue;
+                    }
+                };
+                let keys = text
+                    .chars()
+                    .filter_map(|c| {
+                        let typed = general.layout.get(&c).copied();
+                        if typed.is_none() {
+                            log::warn!("type: no key for character {c:?} in layout; skipping it");
+                        }
+                        typed
+                    })
+                    .collect();
+                let delay = match node.get("delay") {
+                    None => 0u32,
+                    Some(v) => match v.as_integer().and_then(|n| u32::try_from(n).ok()) {
+                        Some(n) => n,
+                        None => {
+                            log::warn!("type delay must be an integer >= 0; defaulting to 0");
+                            0
+                        }
+                    },
+                };
+                steps.push(MacroStep::Type { keys, delay });
+            }
+            "wait_key" => {
+                let key = match node.get(0).and_then(|v| v.as_string()).map(KeyCode::from_str) {
+                    Some(Ok(key)) => key,
+                    _ => {
+                        log::warn!("wait_key requires a key name; skipping");
+                        continue;
+                    }
+                };
+                let down = match node.get(1).and_then(|v| v.as_string()) {
+                    None | Some("down") => true,
+                    Some("up") => false,
+                    Some(other) => {
+                        log::warn!(
+                            "wait_key action must be \"down\" or \"up\", got {other:?}; skipping"
+                        );
+                        continue;
+                    }
+                };
+                let timeout = match node.get("timeout") {
+                    None => None,
+                    Some(v) => match v.as_integer().and_then(|n| u32::try_from(n).ok()) {
+                        Some(n) => Some(n),
+                        None => {
+                            log::warn!(
+                                "wait_key timeout must be an integer >= 0; waiting without one"
+                            );
+                            None
+                        }
+                    },
+                };
+                steps.push(MacroStep::WaitKey { key, down, timeout });
+            }
+            // Only the steps are inlined, they run with this macro's key_delay and type.
+            "call" => match node.get(0).and_then(|v| v.as_string()) {
+                Some(name) => match general.macros.get(name) {
+                    Some(callee) => steps.extend(callee.steps.iter().cloned()),
+                    None => log::warn!("call to invalid macro {name:?}; skipping"),
+                },
+                None => log::warn!("call step requires a macro name; skipping"),
+            },
+            "exec" | "enter" => match node.get(0).and_then(|v| v.as_string()) {
+                Some(arg) if !arg.trim().is_empty() => {
+                    let arg = arg.trim().to_string();
+                    steps.push(if name == "exec" {
+                        MacroStep::Exec(arg)
+                    } else {
+                        MacroStep::Enter(arg)
+                    });
+                }
+                _ => log::warn!("{name} step requires a non-empty string argument; skipping"),
+            },
+            "wait" => {
+                let duration = match node.get(0).and_then(|v| v.as_integer()) {
+                    Some(n) => match u32::try_from(n) {
+                        Ok(v) => v,
+                        Err(_) => {
+                            log::warn!("wait duration must be >= 0, got {n}; skipping");
+                            continue;
+                        }
+                    },
+                    None => {
+                        log::warn!("wait requires a duration in milliseconds; skipping");
+                        continue;
+                    }
+                };
+                let jitter = match node.get("jitter") {
+                    None => 0u32,
+                    Some(v) => match v.as_integer().and_then(|n| u32::try_from(n).ok()) {
+                        Some(n) => n,
+                        None => {
+                            log::warn!("wait jitter must be an integer >= 0; defaulting to 0");
+                            0
+                        }
+                    },
+                };
+                steps.push(MacroStep::Wait { duration, jitter });
+            }
             "repeat" => {
                 let count = match node.get(0).and_then(|v| v.as_integer()) {
                     Some(n) if n >= 2 => match u32::try_from(n) {
@@ -280,7 +755,7 @@ fn parse_macro_steps(doc: &kdl::KdlDocument) -> Vec<MacroStep> {
                     }
                 };
                 let inner = match node.children() {
-                    Some(c) => parse_macro_steps(c),
+                    Some(c) => parse_macro_steps(c, general),
                     None => vec![],
                 };
                 steps.push(MacroStep::Repeat { count, steps: inner });
@@ -309,37 +784,315 @@ fn parse_macro_steps(doc: &kdl::KdlDocument) -> Vec<MacroStep> {
 }
 
 #[cfg(feature = "macro")]
-fn build_macro_hotkey(
+fn parse_macro_def(
     node: &kdl::KdlNode,
-    keysym: KeyCode,
-    modifiers: HashSet<KeyCode>,
-    send: bool,
-    on_release: bool,
-    keycodes_raw: &str,
-) -> Option<Hotkey> {
+    label: &str,
+    general: &GeneralSettings,
+) -> Option<MacroDef> {
     let macro_type = match node.get(1).and_then(|v| v.as_string()) {
         None | Some("simple") => MacroType::Simple,
         Some("endless") => MacroType::Endless,
         Some("hold") => MacroType::Hold,
+        Some("toggle") => MacroType::Toggle,
         Some(unknown) => {
-            log::warn!("unknown macro type {unknown:?} for {keycodes_raw:?}; defaulting to simple");
+            log::warn!("unknown macro type {unknown:?} for {label:?}; defaulting to simple");
             MacroType::Simple
         }
     };
     let steps = match node.children() {
-        Some(c) => parse_macro_steps(c),
+        Some(c) => parse_macro_steps(c, general),
         None => {
-            log::warn!("@macro hotkey has no body: {keycodes_raw:?}");
+            log::warn!("macro has no body: {label:?}");
             vec![]
         }
     };
     if steps.is_empty() {
-        log::warn!("Skipping @macro hotkey: no valid steps: {keycodes_raw:?}");
+        log::warn!("Skipping macro: no valid steps: {label:?}");
         return None;
     }
+    let key_delay = match node.get("key_delay") {
+        None => 0u32,
+        Some(v) => match v.as_integer().and_then(|n| u32::try_from(n).ok()) {
+            Some(n) => n,
+            None => {
+                log::warn!("key_delay must be an integer >= 0 for {label:?}; defaulting to 0");
+                0
+            }
+        },
+    };
+    let stop_key = match node.get("stop_key") {
+        None if macro_type == MacroType::Endless => Some(KeyCode::KEY_ESC),
+        None => None,
+        Some(v) => match v.as_string().map(KeyCode::from_str) {
+            Some(Ok(key)) => Some(key),
+            _ => {
+                log::warn!("stop_key must be a key name for {label:?}; ignoring it");
+                None
+            }
+        },
+    };
+    let policy = match node.get("policy").map(|v| v.as_string()) {
+        None => MacroPolicy::default(),
+        Some(Some("replace")) => MacroPolicy::Replace,
+        Some(Some("ignore")) => MacroPolicy::Ignore,
+        Some(Some("queue")) => MacroPolicy::Queue,
+        Some(Some("parallel")) => MacroPolicy::Parallel,
+        Some(other) => {
+            log::warn!("unknown macro policy {other:?} for {label:?}; defaulting to replace");
+            MacroPolicy::Replace
+        }
+    };
+    let speed = match node.get("speed") {
+        None => 1.0,
+        Some(v) => match v.as_float().or_else(|| v.as_integer().map(|n| n as f64)) {
+            Some(n) if n > 0.0 && n.is_finite() => n,
+            _ => {
+                log::warn!("speed must be a number > 0 for {label:?}; defaulting to 1");
+                1.0
+            }
+        },
+    };
+    let step_interval = match node.get("step_interval") {
+        None => DEFAULT_STEP_INTERVAL_MS,
+        Some(v) => match v.as_integer().and_then(|n| u32::try_from(n).ok()) {
+            Some(n) if n >= 1 => n,
+            _ => {
+                log::warn!(
+                    "step_interval must be an integer >= 1 for {label:?}; defaulting to {DEFAULT_STEP_INTERVAL_MS}"
+                );
+                DEFAULT_STEP_INTERVAL_MS
+            }
+        },
+    };
+    let allow_hotkeys = node.get("hotkeys").and_then(|v| v.as_bool()).unwrap_or(false);
+    let allow_passthrough = node.get("passthrough").and_then(|v| v.as_bool()).unwrap_or(false);
+    Some(MacroDef {
+        macro_type,
+        steps,
+        key_delay,
+        stop_key,
+        policy,
+        allow_hotkeys,
+        allow_passthrough,
+        speed,
+        step_interval,
+    })
+}
+
+/// `"@macro"` with an inline body or `"@macro <name>"` referring to a top-level macro.
+fn is_macro_action(action_value: &str) -> bool {
+    action_value
+        .strip_prefix("@macro")
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+#[cfg(feature = "macro")]
+fn build_macro_hotkey(
+    node: &kdl::KdlNode,
+    keybind: KeyBinding,
+    repeat: RepeatOptions,
+    action_value: &str,
+    keycodes_raw: &str,
+    general: &GeneralSettings,
+) -> Option<Hotkey> {
+    let name = action_value.strip_prefix("@macro").unwrap_or_default().trim();
+    let macro_def = if name.is_empty() {
+        parse_macro_def(node, keycodes_raw, general)?
+    } else {
+        match general.macros.get(name) {
+            Some(macro_def) => macro_def.clone(),
+            None => {
+                log::warn!("Skipping {keycodes_raw:?}: unknown macro {name:?}");
+                return None;
+            }
+        }
+    };
+    let trigger = trigger_name(&keybind.modifiers, &format!("{:?}", keybind.keysym));
     Some(Hotkey {
-        keybind: KeyBinding { keysym, modifiers, send, on_release },
-        action: HotkeyAction::Macro(MacroDef { macro_type, steps }),
+        keybind,
+        action: HotkeyAction::Macro(macro_def),
+        repeat,
+        trigger,
+        command: CommandOptions::default(),
+    })
+}
+
+// Collects the names used by `call` steps, including inside `repeat` blocks.
+#[cfg(feature = "macro")]
+fn called_macros<'a>(doc: &'a kdl::KdlDocument, names: &mut Vec<&'a str>) {
+    for node in doc.nodes() {
+        if node.name().value() == "call"
+            && let Some(name) = node.get(0).and_then(|v| v.as_string())
+        {
+            names.push(name);
+        }
+        if let Some(children) = node.children() {
+            called_macros(children, names);
+        }
+    }
+}
+
+// Macros are parsed callees first so `call` can inline an already finished definition.
+// A `call` that would close a cycle fails the whole config.
+#[cfg(feature = "macro")]
+fn parse_named_macro<'a>(
+    name: &'a str,
+    nodes: &HashMap<&'a str, &'a kdl::KdlNode>,
+    stack: &mut Vec<&'a str>,
+    general: &mut GeneralSettings,
+) -> Result<(), Error> {
+    if general.macros.contains_key(name) {
+        return Ok(());
+    }
+    if let Some(pos) = stack.iter().position(|&n| n == name) {
+        let chain = stack[pos..].iter().chain([&name]).copied().collect::<Vec<_>>().join(" -> ");
+        return Err(Error::Parse(format!("Recursive macro call: {chain}")));
+    }
+    let node = nodes[name];
+    stack.push(name);
+    let mut callees = vec![];
+    if let Some(children) = node.children() {
+        called_macros(children, &mut callees);
+    }
+    for callee in callees {
+        parse_named_macro(callee, nodes, stack, general)?;
+    }
+    stack.pop();
+    if let Some(macro_def) = parse_macro_def(node, name, general) {
+        general.macros.insert(name.to_string(), macro_def);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "macro")]
+fn parse_named_macros(doc: &kdl::KdlDocument, general: &mut GeneralSettings) -> Result<(), Error> {
+    let mut nodes: HashMap<&str, &kdl::KdlNode> = HashMap::new();
+    for node in doc.nodes().iter().filter(|n| n.name().value() == MACRO_STATEMENT) {
+        match node.get(0).and_then(|v| v.as_string()) {
+            Some(name) => {
+                if nodes.insert(name, node).is_some() {
+                    log::warn!("Macro {name:?} is defined more than once; using the last one");
+                }
+            }
+            None => log::warn!("Top-level macro requires a name: macro \"<name>\" {{ ... }}"),
+        }
+    }
+    // Calls from inline macros are checked here too, `call` only ever appears in macro steps.
+    let mut callees = vec![];
+    called_macros(doc, &mut callees);
+    if let Some(callee) = callees.iter().find(|callee| !nodes.contains_key(*callee)) {
+        return Err(Error::Parse(format!("call to unknown macro {callee:?}")));
+    }
+    let mut names = nodes.keys().copied().collect::<Vec<_>>();
+    names.sort_unstable();
+    for name in names {
+        parse_named_macro(name, &nodes, &mut vec![], general)?;
+    }
+    Ok(())
+}
+
+fn parse_axis_hotkey(
+    node: &kdl::KdlNode,
+    direction: WheelDirection,
+    modifier_names: &[&str],
+    action_value: &str,
+    keycodes_raw: &str,
+    general: &GeneralSettings,
+) -> Option<AxisHotkey> {
+    let modifiers = match modifier_names
+        .iter()
+        .map(|s| KeyCode::from_str(s))
+        .collect::<Result<HashSet<_>, _>>()
+    {
+        Ok(tokens) if tokens.iter().all(|token| ALLOWED_MODIFIERS.contains(token)) => tokens,
+        _ => {
+            log::warn!("Invalid modifier for wheel binding: {keycodes_raw:?}");
+            return None;
+        }
+    };
+
+    if is_macro_action(action_value) {
+        #[cfg(feature = "macro")]
+        return build_macro_hotkey(
+            node,
+            KeyBinding {
+                keysym: KeyCode::KEY_RESERVED,
+                modifiers: modifiers.clone(),
+                send: false,
+                on_release: false,
+            },
+            RepeatOptions::default(),
+            action_value,
+            keycodes_raw,
+            general,
+        )
+        .map(|hk| AxisHotkey {
+            direction,
+            modifiers,
+            action: hk.action,
+            command: CommandOptions::default(),
+        });
+        #[cfg(not(feature = "macro"))]
+        {
+            let _ = (node, general);
+            log::warn!("@macro hotkey ignored (macro feature not enabled): {keycodes_raw:?}");
+            return None;
+        }
+    }
+    if KeyCode::from_str(action_value).is_ok() {
+        log::warn!("Wheel directions cannot be remapped to keys: {keycodes_raw:?}");
+        return None;
+    }
+    let action = action_value.strip_suffix('\n').unwrap_or(action_value).to_string();
+    if action_has_empty_segment(&action) {
+        log::warn!("Skipping hotkey '{keycodes_raw}': action has empty '&&' segment: {action:?}");
+        return None;
+    }
+    Some(AxisHotkey {
+        direction,
+        modifiers,
+        action: HotkeyAction::Shell(action),
+        command: parse_command_options(node, keycodes_raw),
+    })
+}
+
+fn parse_switch_hotkey(
+    node: &kdl::KdlNode,
+    switch: SwitchCode,
+    keycodes_raw: &str,
+) -> Option<SwitchHotkey> {
+    if !crate::uinput::get_all_switches().contains(&switch) {
+        log::warn!("Unsupported switch: {keycodes_raw:?}");
+        return None;
+    }
+    let state = match node.get(0).and_then(|v| v.as_string()) {
+        Some("on") => true,
+        Some("off") => false,
+        other => {
+            log::warn!("Switch state must be \"on\" or \"off\", got {other:?}: {keycodes_raw:?}");
+            return None;
+        }
+    };
+    let action = match node.get(1).and_then(|v| v.as_string()) {
+        Some(action) => action.strip_suffix('\n').unwrap_or(action).to_string(),
+        None => {
+            log::warn!("Missing action for switch line: {keycodes_raw:?}");
+            return None;
+        }
+    };
+    if is_macro_action(&action) {
+        log::warn!("@macro is not supported on switches: {keycodes_raw:?}");
+        return None;
+    }
+    if action_has_empty_segment(&action) {
+        log::warn!("Skipping hotkey '{keycodes_raw}': action has empty '&&' segment: {action:?}");
+        return None;
+    }
+    Some(SwitchHotkey {
+        switch,
+        state,
+        action: HotkeyAction::Shell(action),
+        command: parse_command_options(node, keycodes_raw),
     })
 }
 
@@ -349,7 +1102,13 @@ fn parse_mode(mode_name: &str, mode_node: &kdl::KdlNode, general: &GeneralSettin
         hotkeys: vec![],
         remaps: HashMap::new(),
         unbinds: vec![],
-        options: ModeOptions { swallow: general.swallow, oneoff: general.oneoff },
+        axis_hotkeys: vec![],
+        switch_hotkeys: vec![],
+        options: ModeOptions {
+            swallow: general.swallow,
+            oneoff: general.oneoff,
+            shell: general.shell.clone(),
+        },
     };
 
     let children = match mode_node.children() {
@@ -360,6 +1119,13 @@ fn parse_mode(mode_name: &str, mode_node: &kdl::KdlNode, general: &GeneralSettin
     for hotkey_node in children.nodes() {
         let keycodes_raw = hotkey_node.name().value().to_string();
 
+        if let Ok(switch) = SwitchCode::from_str(&keycodes_raw) {
+            if let Some(switch_hotkey) = parse_switch_hotkey(hotkey_node, switch, &keycodes_raw) {
+                mode.switch_hotkeys.push(switch_hotkey);
+            }
+            continue;
+        }
+
         let action_value = match hotkey_node.get(0) {
             Some(val) => match val.as_string() {
                 Some(s) => s.to_string(),
@@ -376,23 +1142,46 @@ fn parse_mode(mode_name: &str, mode_node: &kdl::KdlNode, general: &GeneralSettin
 
         let on_release = hotkey_node.get("on_release").and_then(|v| v.as_bool()).unwrap_or(false);
         let send = hotkey_node.get("send").and_then(|v| v.as_bool()).unwrap_or(false);
+        let repeat = parse_repeat(hotkey_node, general.repeat, &keycodes_raw);
+        let command = parse_command_options(hotkey_node, &keycodes_raw);
 
         let keycodes: String = keycodes_raw.chars().filter(|&c| c != ' ' && c != '\t').collect();
         let objects = keycodes.split('+').collect::<Vec<_>>();
 
+        if let Some((last, modifier_names)) = objects.split_last()
+            && let Ok(direction) = WheelDirection::from_str(last)
+        {
+            if let Some(axis_hotkey) = parse_axis_hotkey(
+                hotkey_node,
+                direction,
+                modifier_names,
+                &action_value,
+                &keycodes_raw,
+                general,
+            ) {
+                mode.axis_hotkeys.push(axis_hotkey);
+            }
+            continue;
+        }
+
         if objects.len() == 1 && !objects[0].starts_with('<') {
             let key_str = objects[0];
             match KeyCode::from_str(key_str) {
                 Ok(from_key) => {
-                    if action_value == "@macro" {
+                    if is_macro_action(&action_value) {
                         #[cfg(feature = "macro")]
                         if let Some(hk) = build_macro_hotkey(
                             hotkey_node,
-                            from_key,
-                            HashSet::new(),
-                            send,
-                            on_release,
+                            KeyBinding {
+                                keysym: from_key,
+                                modifiers: HashSet::new(),
+                                send,
+                                on_release,
+                            },
+                            repeat,
+                            &action_value,
                             &keycodes_raw,
+                            general,
                         ) {
                             mode.hotkeys.push(hk);
                         };
@@ -422,6 +1211,9 @@ fn parse_mode(mode_name: &str, mode_node: &kdl::KdlNode, general: &GeneralSettin
                             on_release,
                         },
                         action: HotkeyAction::Shell(action),
+                        repeat,
+                        trigger: format!("{from_key:?}"),
+                        command,
                     });
                     continue;
                 }
@@ -527,7 +1319,7 @@ fn parse_mode(mode_name: &str, mode_node: &kdl::KdlNode, general: &GeneralSettin
             commands.push(action_value.clone());
         }
 
-        if action_value == "@macro" {
+        if is_macro_action(&action_value) {
             if keys.len() > 1 {
                 log::warn!(
                     "@macro does not support key group expansion: {keycodes_raw:?}; skipping"
@@ -537,11 +1329,11 @@ fn parse_mode(mode_name: &str, mode_node: &kdl::KdlNode, general: &GeneralSettin
             #[cfg(feature = "macro")]
             if let Some(hk) = build_macro_hotkey(
                 hotkey_node,
-                keys[0],
-                modifiers.clone(),
-                send,
-                on_release,
+                KeyBinding { keysym: keys[0], modifiers: modifiers.clone(), send, on_release },
+                repeat,
+                &action_value,
                 &keycodes_raw,
+                general,
             ) {
                 mode.hotkeys.push(hk);
             };
@@ -568,6 +1360,9 @@ fn parse_mode(mode_name: &str, mode_node: &kdl::KdlNode, general: &GeneralSettin
                     on_release,
                 },
                 action: HotkeyAction::Shell(action),
+                repeat,
+                trigger: trigger_name(&modifiers, &format!("{:?}", keys[i])),
+                command: command.clone(),
             });
         }
     }
@@ -576,6 +1371,12 @@ fn parse_mode(mode_name: &str, mode_node: &kdl::KdlNode, general: &GeneralSettin
     for hotkey in mode.hotkeys.iter() {
         log::debug!("Hotkey: {hotkey:?}");
     }
+    for axis_hotkey in mode.axis_hotkeys.iter() {
+        log::debug!("Wheel hotkey: {axis_hotkey:?}");
+    }
+    for switch_hotkey in mode.switch_hotkeys.iter() {
+        log::debug!("Switch hotkey: {switch_hotkey:?}");
+    }
     log::debug!("after hotkeys");
 
     mode
@@ -589,13 +1390,21 @@ pub fn load(path: &Path) -> Result<Config, Error> {
 pub fn load_from_str(content: &str) -> Result<Config, Error> {
     let doc: kdl::KdlDocument =
         content.parse().map_err(|e: kdl::KdlError| Error::Parse(e.to_string()))?;
-    let general = parse_general(&doc);
+    #[cfg_attr(not(feature = "macro"), allow(unused_mut))]
+    let mut general = parse_general(&doc);
+    #[cfg(feature = "macro")]
+    parse_named_macros(&doc, &mut general)?;
     let mut modes: Vec<Mode> = Vec::new();
     for node in doc.nodes() {
         let name = node.name().value();
         if name == "general" {
             continue;
         }
+        if name == MACRO_STATEMENT {
+            #[cfg(not(feature = "macro"))]
+            log::warn!("Top-level macro ignored (macro feature not enabled)");
+            continue;
+        }
         modes.push(parse_mode(name, node, &general));
     }
     let default_mode =
@@ -635,6 +1444,9 @@ impl fmt::Display for Error {
 //pub const IMPORT_STATEMENT: &str = "include";
 //pub const UNBIND_STATEMENT: &str = "ignore";
 pub const MODE_ENTER_STATEMENT: &str = "@enter";
+pub const RECORD_STATEMENT: &str = "@record";
+pub const PLAY_STATEMENT: &str = "@play";
+pub const MACRO_STATEMENT: &str = "macro";
 
 pub const ALLOWED_MODIFIERS: [KeyCode; 8] = [
     evdev::KeyCode::KEY_LEFTMETA,
@@ -646,3 +1458,165 @@ pub const ALLOWED_MODIFIERS: [KeyCode; 8] = [
     evdev::KeyCode::KEY_LEFTSHIFT,
     evdev::KeyCode::KEY_RIGHTSHIFT,
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn master(config: &Config) -> &Mode {
+        &config.modes[config.default_mode]
+    }
+
+    #[test]
+    fn repeat_defaults_come_from_general() {
+        let config = load_from_str(
+            "master {\n  KEY_A \"true\"\n}\ngeneral {\n  repeat #false\n  repeat_delay 400\n}\n",
+        )
+        .unwrap();
+        let repeat = master(&config).hotkeys[0].repeat;
+        assert_eq!(repeat, RepeatOptions { enabled: false, delay: 400, rate: 250 });
+    }
+
+    #[test]
+    fn repeat_properties_override_general() {
+        let config = load_from_str(
+            "master {\n  KEY_A \"true\" repeat=#true repeat_delay=100 repeat_rate=50\n}\ngeneral {\n  repeat #false\n}\n",
+        )
+        .unwrap();
+        let repeat = master(&config).hotkeys[0].repeat;
+        assert_eq!(repeat, RepeatOptions { enabled: true, delay: 100, rate: 50 });
+    }
+
+    #[test]
+    fn instance_option_is_parsed() {
+        let config = load_from_str(
+            "master {\n  KEY_A \"rofi\" instance=\"toggle\" timeout=5000\n  KEY_B \"true\" instance=\"twice\" timeout=0\n  SW_LID \"on\" \"true\" instance=\"single\"\n}\n",
+        )
+        .unwrap();
+        let mode = master(&config);
+        assert_eq!(mode.hotkeys[0].command.instance, Instance::Toggle);
+        assert_eq!(mode.hotkeys[0].command.timeout, Some(5000));
+        assert_eq!(mode.hotkeys[1].command.instance, Instance::Multiple);
+        assert_eq!(mode.hotkeys[1].command.timeout, None);
+        assert_eq!(mode.switch_hotkeys[0].to_hotkey().command.instance, Instance::Single);
+    }
+
+    #[test]
+    fn shell_env_and_cwd_are_parsed() {
+        let config = load_from_str(
+            "master {\n  KEY_A \"make\" cwd=\"~/src\" {\n    env {\n      CC \"clang\"\n      JOBS 4\n    }\n  }\n}\ngeneral {\n  shell \"/bin/bash\" \"-lc\"\n}\n",
+        )
+        .unwrap();
+        let mode = master(&config);
+        assert_eq!(mode.options.shell, ["/bin/bash", "-lc"]);
+        let command = &mode.hotkeys[0].command;
+        assert_eq!(command.cwd.as_deref(), Some("~/src"));
+        assert_eq!(command.env, [("CC".to_string(), "clang".to_string())]);
+    }
+
+    #[test]
+    fn wheel_bindings_are_parsed_with_modifiers() {
+        let config = load_from_str(
+            "master {\n  KEY_LEFTMETA+REL_WHEEL_UP \"swaymsg workspace prev\"\n  REL_HWHEEL_RIGHT \"true\"\n  KEY_A+REL_WHEEL_DOWN \"true\"\n}\n",
+        )
+        .unwrap();
+        let axis_hotkeys = &master(&config).axis_hotkeys;
+        assert_eq!(axis_hotkeys.len(), 2);
+        assert_eq!(axis_hotkeys[0].direction, WheelDirection::Up);
+        assert!(axis_hotkeys[0].modifiers.contains(&KeyCode::KEY_LEFTMETA));
+        assert_eq!(axis_hotkeys[1].direction, WheelDirection::Right);
+        assert!(axis_hotkeys[1].modifiers.is_empty());
+    }
+
+    #[test]
+    fn switch_bindings_require_state_and_action() {
+        let config = load_from_str(
+            "master {\n  SW_LID \"on\" \"systemctl suspend\"\n  SW_TABLET_MODE \"off\" \"true\"\n  SW_DOCK \"sideways\" \"true\"\n  SW_HEADPHONE_INSERT \"on\"\n}\n",
+        )
+        .unwrap();
+        let switch_hotkeys = &master(&config).switch_hotkeys;
+        assert_eq!(switch_hotkeys.len(), 2);
+        assert_eq!(switch_hotkeys[0].switch, SwitchCode::SW_LID);
+        assert!(switch_hotkeys[0].state);
+        assert_eq!(switch_hotkeys[0].action, HotkeyAction::Shell("systemctl suspend".to_string()));
+        assert_eq!(switch_hotkeys[1].switch, SwitchCode::SW_TABLET_MODE);
+        assert!(!switch_hotkeys[1].state);
+    }
+
+    #[cfg(feature = "macro")]
+    fn macro_steps(config: &Config) -> &[MacroStep] {
+        match &master(config).hotkeys[0].action {
+            HotkeyAction::Macro(macro_def) => &macro_def.steps,
+            other => panic!("expected a macro, got {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "macro")]
+    #[test]
+    fn type_step_uses_layout_overrides() {
+        let config = load_from_str(
+            "master {\n  KEY_A \"@macro\" {\n    type \"zA!\" delay=5\n  }\n}\ngeneral {\n  layout {\n    \"z\" KEY_Y\n  }\n}\n",
+        )
+        .unwrap();
+        let keys = vec![
+            TypedKey { key: KeyCode::KEY_Y, shift: false },
+            TypedKey { key: KeyCode::KEY_A, shift: true },
+            TypedKey { key: KeyCode::KEY_1, shift: true },
+        ];
+        assert_eq!(macro_steps(&config), [MacroStep::Type { keys, delay: 5 }]);
+    }
+
+    #[cfg(feature = "macro")]
+    #[test]
+    fn named_macros_are_inlined_and_bad_calls_are_rejected() {
+        let config = load_from_str(
+            "macro \"inner\" key_delay=9 {\n  KEY_B\n}\nmacro \"outer\" key_delay=5 {\n  KEY_A\n  call \"inner\"\n}\nmaster {\n  KEY_1 \"@macro outer\"\n  KEY_3 \"@macro missing\"\n}\n",
+        )
+        .unwrap();
+        assert_eq!(config.modes.len(), 1);
+        let hotkeys = &master(&config).hotkeys;
+        assert_eq!(hotkeys.len(), 1);
+        let click = |key| MacroStep::KeyAction { key, action: KeyAction::Click };
+        match &hotkeys[0].action {
+            HotkeyAction::Macro(macro_def) => {
+                assert_eq!(macro_def.steps, [click(KeyCode::KEY_A), click(KeyCode::KEY_B)]);
+                assert_eq!(macro_def.key_delay, 5);
+            }
+            other => panic!("expected a macro, got {other:?}"),
+        }
+
+        let error = |content| match load_from_str(content) {
+            Err(Error::Parse(msg)) => msg,
+            _ => panic!("expected a parse error for {content:?}"),
+        };
+        assert_eq!(
+            error(
+                "macro \"ping\" {\n  call \"pong\"\n}\nmacro \"pong\" {\n  call \"ping\"\n}\nmaster {\n  KEY_2 \"@macro ping\"\n}\n"
+            ),
+            "Recursive macro call: ping -> pong -> ping"
+        );
+        assert_eq!(
+            error("master {\n  KEY_2 \"@macro\" {\n    call \"nope\"\n  }\n}\n"),
+            "call to unknown macro \"nope\""
+        );
+    }
+
+    #[cfg(feature = "macro")]
+    #[test]
+    fn macro_policy_and_trigger_are_parsed() {
+        let config = load_from_str(
+            "master {\n  KEY_LEFTSHIFT+KEY_LEFTCTRL+KEY_A \"@macro\" policy=\"queue\" hotkeys=#true {\n    KEY_B\n  }\n}\n",
+        )
+        .unwrap();
+        let hotkey = &master(&config).hotkeys[0];
+        assert_eq!(hotkey.trigger, "KEY_LEFTCTRL+KEY_LEFTSHIFT+KEY_A");
+        match &hotkey.action {
+            HotkeyAction::Macro(macro_def) => {
+                assert_eq!(macro_def.policy, MacroPolicy::Queue);
+                assert!(macro_def.allow_hotkeys);
+                assert!(!macro_def.allow_passthrough);
+            }
+            other => panic!("expected a macro, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Control socket for driving recording from outside swhkdp, e.g. `swhkdp --control "record demo"`.
+//!
+//! A request is one line of text, the reply is text and ends with the connection.
+
+use nix::unistd::{Uid, chown};
+use std::fs::{self, Permissions};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+const SOCKET_NAME: &str = "swhkdp-control.sock";
+const MAX_REQUEST_LEN: u64 = 1024;
+
+/// A request line and where its reply goes.
+pub type Request = (String, oneshot::Sender<String>);
+
+pub fn socket_path(runtime_dir: &Path) -> PathBuf {
+    runtime_dir.join(SOCKET_NAME)
+}
+
+/// Listens on `path`, which only `uid` and root may use, and forwards each request to `tx`.
+pub fn listen(path: &Path, uid: u32, tx: mpsc::Sender<Request>) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    chown(path, Some(Uid::from_raw(uid)), None)?;
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::warn!("Failed to accept a control connection: {e}");
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, uid, tx).await {
+                    log::debug!("Control connection failed: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(stream: UnixStream, uid: u32, tx: mpsc::Sender<Request>) -> io::Result<()> {
+    // The socket's mode already keeps others out, this also holds if it gets loosened.
+    let peer = stream.peer_cred()?.uid();
+    if peer != uid && peer != 0 {
+        log::warn!("Rejected control connection from uid {peer}");
+        return Ok(());
+    }
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader.take(MAX_REQUEST_LEN)).read_line(&mut line).await?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if tx.send((line.trim().to_string(), reply_tx)).await.is_err() {
+        return Ok(());
+    }
+    if let Ok(reply) = reply_rx.await {
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+pub fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        log::warn!("Unable to remove {}: {e}", path.display());
+    }
+}
+
+/// Sends `request` to the swhkdp running for the calling user and returns its reply.
+pub fn send(request: &str) -> io::Result<String> {
+    let runtime_dir = PathBuf::from(format!("/run/user/{}", Uid::current()));
+    let mut stream = std::os::unix::net::UnixStream::connect(socket_path(&runtime_dir))?;
+    writeln!(stream, "{request}")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_reach_the_main_loop_and_replies_come_back() {
+        let dir = std::env::temp_dir().join(format!("swhkdp-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = socket_path(&dir);
+        let (tx, mut rx) = mpsc::channel(1);
+        listen(&path, Uid::current().as_raw(), tx).unwrap();
+        let client = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut stream = std::os::unix::net::UnixStream::connect(path).unwrap();
+                stream.write_all(b"show demo\n").unwrap();
+                stream.shutdown(Shutdown::Write).unwrap();
+                let mut reply = String::new();
+                stream.read_to_string(&mut reply).unwrap();
+                reply
+            })
+        };
+        let (request, reply_tx) = rx.recv().await.unwrap();
+        assert_eq!(request, "show demo");
+        reply_tx.send("ok\n".to_string()).unwrap();
+        assert_eq!(client.await.unwrap(), "ok\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,7 +1,7 @@
 use crate::config::Value;
 use clap::Parser;
 use config::Hotkey;
-use evdev::{AttributeSet, Device, EventSummary, KeyCode};
+use evdev::{AttributeSet, Device, EventSummary, KeyCode, RelativeAxisCode};
 use nix::{
     sys::stat::{Mode, umask},
     unistd::Uid,
@@ -9,11 +9,13 @@ use nix::{
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
 #[cfg(feature = "macro")]
+use std::collections::VecDeque;
+#[cfg(feature = "macro")]
 use std::sync::Arc;
 #[cfg(feature = "macro")]
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
     fs,
     fs::Permissions,
@@ -24,8 +26,6 @@ use std::{
     process::{exit, id},
 };
 use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, RefreshKind, System, UpdateKind};
-use tokio::io::AsyncWriteExt;
-use tokio::net::UnixStream;
 use tokio::select;
 use tokio::time::Duration;
 use tokio::time::{Instant, sleep};
@@ -36,6 +36,10 @@ use tokio_udev::{AsyncMonitorSocket, EventType, MonitorBuilder};
 // TODO: #shrink
 const IPC_QUEUE_CAP: usize = 256;
 
+/// How long shutdown waits for running macros to let go of their keys and for queued
+/// commands to reach swhks.
+const SHUTDOWN_GRACE_MS: u64 = 1000;
+
 /// The fixed system config path. In release builds this is the only config
 const RELEASE_CONFIG_PATH: &str = "/etc/swhkdp/config.kdl";
 
@@ -43,31 +47,214 @@ const RELEASE_CONFIG_PATH: &str = "/etc/swhkdp/config.kdl";
 #[cfg(feature = "macro")]
 const MACRO_QUEUE_CAP: usize = 256;
 
+// Control requests are answered one at a time by the main loop.
+const CONTROL_QUEUE_CAP: usize = 8;
+
 mod config;
+mod control;
 mod environ;
+mod ipc;
+#[cfg(feature = "macro")]
+mod layout;
 #[cfg(feature = "macro")]
 mod macro_runner;
 #[cfg(not(debug_assertions))]
 mod perms;
+#[cfg(feature = "macro")]
+mod recorder;
 mod rel_mask;
 mod uinput;
 
 #[cfg(feature = "macro")]
 struct MacroState {
+    id: u64,
+    trigger: String,
     stop: Arc<AtomicBool>,
     handle: tokio::task::JoinHandle<()>,
     macro_type: config::MacroType,
     trigger_keybind: config::KeyBinding,
+    stop_key: Option<KeyCode>,
+    allow_hotkeys: bool,
+    allow_passthrough: bool,
+    // Physical key events for `wait_key` steps, dropped when the macro isn't keeping up.
+    key_tx: tokio::sync::mpsc::Sender<(KeyCode, i32)>,
+}
+
+/// Running macros and the `policy="queue"` triggers waiting for their previous run.
+#[cfg(feature = "macro")]
+#[derive(Default)]
+struct MacroSet {
+    running: Vec<MacroState>,
+    queued: VecDeque<(config::KeyBinding, String, config::MacroDef)>,
+    next_id: u64,
+    recording: Option<recorder::Recorder>,
+    // `@record` slots, kept until the daemon exits.
+    slots: HashMap<String, config::MacroDef>,
+}
+
+#[cfg(feature = "macro")]
+impl MacroSet {
+    fn is_running(&self, trigger: &str) -> bool {
+        self.running.iter().any(|m| m.trigger == trigger)
+    }
+
+    fn hotkeys_blocked(&self) -> bool {
+        self.running.iter().any(|m| !m.allow_hotkeys)
+    }
+
+    fn passthrough_blocked(&self) -> bool {
+        self.running.iter().any(|m| !m.allow_passthrough)
+    }
+
+    // A hotkey may always re-fire its own running macro so the policy gets a say.
+    fn hotkey_allowed(&self, trigger: &str) -> bool {
+        !self.hotkeys_blocked() || self.is_running(trigger)
+    }
+
+    fn stop_trigger(&self, trigger: &str) {
+        for state in self.running.iter().filter(|m| m.trigger == trigger) {
+            state.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // A toggle macro is stopped by its next trigger, whatever kind of input that is.
+    fn stop_toggle(&self, trigger: &str) -> bool {
+        let mut stopped = false;
+        for state in self
+            .running
+            .iter()
+            .filter(|m| m.trigger == trigger && matches!(m.macro_type, config::MacroType::Toggle))
+        {
+            state.stop.store(true, Ordering::Relaxed);
+            stopped = true;
+        }
+        stopped
+    }
+
+    fn stop_all(&mut self) {
+        for state in &self.running {
+            state.stop.store(true, Ordering::Relaxed);
+        }
+        self.queued.clear();
+    }
+
+    // Drops tasks that ended without reporting back, e.g. after a panic.
+    fn reap(&mut self) {
+        self.running.retain(|m| !m.handle.is_finished());
+    }
+}
+
+#[cfg(not(feature = "macro"))]
+#[derive(Default)]
+struct MacroSet {}
+
+#[cfg(not(feature = "macro"))]
+impl MacroSet {
+    fn passthrough_blocked(&self) -> bool {
+        false
+    }
+
+    fn stop_all(&mut self) {}
+
+    fn hotkey_allowed(&self, _: &str) -> bool {
+        true
+    }
 }
 
+#[cfg(feature = "macro")]
+use macro_runner::MacroOutput;
+
 #[cfg(not(feature = "macro"))]
-struct MacroState;
+enum MacroOutput {}
+
+/// The key and pointer virtual device, remembering which keys it holds down so they can be
+/// released when input is interrupted.
+struct VirtualKeyboard {
+    device: evdev::uinput::VirtualDevice,
+    held: HashSet<KeyCode>,
+    // The part of `held` that running macros pressed, they release those keys themselves.
+    macro_held: HashSet<KeyCode>,
+}
+
+impl VirtualKeyboard {
+    fn new(device: evdev::uinput::VirtualDevice) -> VirtualKeyboard {
+        VirtualKeyboard { device, held: HashSet::new(), macro_held: HashSet::new() }
+    }
+
+    #[cfg(feature = "macro")]
+    fn emit_macro(&mut self, events: &[evdev::InputEvent]) {
+        for event in events {
+            if let EventSummary::Key(_, key, value) = event.destructure() {
+                match value {
+                    1 => {
+                        self.macro_held.insert(key);
+                    }
+                    0 => {
+                        self.macro_held.remove(&key);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.emit(events);
+    }
+
+    fn emit(&mut self, events: &[evdev::InputEvent]) {
+        for event in events {
+            if let EventSummary::Key(_, key, value) = event.destructure() {
+                match value {
+                    1 => {
+                        self.held.insert(key);
+                    }
+                    0 => {
+                        self.held.remove(&key);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        emit_or_warn(&mut self.device, events);
+    }
+
+    fn release_all(&mut self) {
+        self.macro_held.clear();
+        self.release(|_| true);
+    }
+
+    /// Releases what was held before a mode switch, except keys a running macro holds on purpose.
+    fn release_for_mode_switch(&mut self) {
+        let macro_held = std::mem::take(&mut self.macro_held);
+        self.release(|key| !macro_held.contains(key));
+        self.macro_held = macro_held;
+    }
+
+    fn release(&mut self, which: impl Fn(&KeyCode) -> bool) {
+        let keys: Vec<KeyCode> = self.held.iter().copied().filter(|key| which(key)).collect();
+        if keys.is_empty() {
+            return;
+        }
+        let events: Vec<evdev::InputEvent> = keys
+            .iter()
+            .map(|key| {
+                self.held.remove(key);
+                evdev::InputEvent::new(evdev::EventType::KEY.0, key.0, 0)
+            })
+            .collect();
+        log::debug!("Releasing {} held virtual key(s)", events.len());
+        emit_or_warn(&mut self.device, &events);
+    }
+}
 
 struct DeviceState {
     state_modifiers: AttributeSet<KeyCode>,
     state_modifiers_count: usize,
     state_keysyms: AttributeSet<KeyCode>,
     allowed_rel: u16,
+    // Partial hi-res scroll per axis (vertical, horizontal), see `rel_mask::wheel_detents`.
+    hires_wheel: [i32; 2],
+    name: String,
+    // Not grabbed, so its switch events already reach everyone else.
+    switch_only: bool,
 }
 
 impl DeviceState {
@@ -85,6 +272,9 @@ impl DeviceState {
             state_modifiers_count: 0,
             state_keysyms: AttributeSet::new(),
             allowed_rel,
+            hires_wheel: [0; 2],
+            name: device.name().unwrap_or("[unknown]").to_string(),
+            switch_only: is_switch_only(device),
         }
     }
 }
@@ -98,8 +288,8 @@ struct Args {
     #[arg(short = 'c', long, value_name = "FILE")]
     config: Option<PathBuf>,
 
-    /// Set a custom repeat cooldown duration. Default is 250ms.
-    #[arg(short = 'C', long)]
+    /// Deprecated, use `repeat_rate` in the `general` block of the config instead.
+    #[arg(short = 'C', long, hide = true)]
     cooldown: Option<u64>,
 
     /// Enable Debug Mode
@@ -121,12 +311,16 @@ struct Args {
     /// Verify config
     #[arg(long = "verify-config")]
     verify_config: bool,
+
+    /// Send a command to the running swhkdp and print the reply:
+    /// "record <slot>", "stop", "play <slot>" or "show <slot>"
+    #[arg(long, value_name = "COMMAND")]
+    control: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
-    let default_cooldown: u64 = 250;
 
     if args.debug {
         env_logger::Builder::from_env(env_logger::Env::default().filter_or("RUST_LOG", "debug"))
@@ -137,12 +331,31 @@ async fn main() -> Result<(), Box<dyn Error>> {
         )
         .init();
     }
+
     log::debug!("Logger initialized.");
 
+    if args.cooldown.is_some() {
+        log::warn!(
+            "--cooldown is deprecated and ignored, set repeat_rate in the general block of the config instead."
+        );
+    }
+
     if args.verify_config {
         return run_verify_mode(&resolve_config_path(&args));
     }
 
+    // A client of the running daemon, runs as the user.
+    if let Some(request) = &args.control {
+        match control::send(request) {
+            Ok(reply) => print!("{reply}"),
+            Err(e) => {
+                log::error!("Failed to reach swhkdp's control socket: {e}");
+                exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     check_pkexec();
 
     let env = environ::Env::construct();
@@ -211,6 +424,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut modes = config.modes;
     let mut current_mode: usize = config.default_mode;
     let mut default_mode: usize = config.default_mode;
+    let mut read_switches = has_switch_hotkeys(&modes);
     let arg_add_devices = args.devices;
     let arg_ignore_devices = args.ignore_devices;
 
@@ -223,7 +437,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         if arg_add_devices.is_empty() {
             log::debug!("Attempting to find all supported devices file descriptors.");
             evdev::enumerate()
-                .filter(|(_, dev)| !to_ignore(dev) && check_device_is_supported(dev))
+                .filter(|(_, dev)| !to_ignore(dev) && check_device_is_supported(dev, read_switches))
                 .collect()
         } else {
             evdev::enumerate().filter(|(_, dev)| !to_ignore(dev) && to_add(dev)).collect()
@@ -249,7 +463,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // virtual devices, one for keys and relative axes (`uinput_device`) and another one
     // just for switches (`uinput_switches_device`).
     let mut uinput_device = match uinput::create_uinput_device() {
-        Ok(dev) => dev,
+        Ok(dev) => VirtualKeyboard::new(dev),
         Err(e) => {
             log::error!("Failed to create uinput device: {e:#?}");
             exit(1);
@@ -267,8 +481,6 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut udev =
         AsyncMonitorSocket::new(MonitorBuilder::new()?.match_subsystem("input")?.listen()?)?;
 
-    let repeat_cooldown_duration: u64 = args.cooldown.unwrap_or(default_cooldown);
-
     let mut signals = Signals::new([
         SIGUSR1, SIGUSR2, SIGHUP, SIGABRT, SIGBUS, SIGCHLD, SIGCONT, SIGINT, SIGPIPE, SIGQUIT,
         SIGSYS, SIGTERM, SIGTRAP, SIGTSTP, SIGVTALRM, SIGXCPU, SIGXFSZ,
@@ -277,12 +489,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut execution_is_paused = false;
     let mut last_hotkey: Option<config::Hotkey> = None;
     let mut pending_release: bool = false;
-    let mut active_macro: Option<MacroState> = None;
+    // Device of the latest input event, exported to commands as `$SWHKDP_DEVICE`.
+    let mut source_device = String::new();
+    // Device `last_hotkey` came from, for its repeats and on-release dispatch.
+    let mut last_hotkey_device = String::new();
+    let mut macros = MacroSet::default();
     let mut device_states = HashMap::new();
     let mut device_stream_map = StreamMap::new();
 
     for (path, mut device) in supported_devices.into_iter() {
-        let _ = device.grab();
+        grab(&mut device);
         let path = match path.to_str() {
             Some(p) => p,
             None => {
@@ -301,31 +517,45 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // never drop macro events
     #[cfg(feature = "macro")]
     let (macro_emit_tx, mut macro_emit_rx) =
-        tokio::sync::mpsc::channel::<Vec<evdev::InputEvent>>(MACRO_QUEUE_CAP);
+        tokio::sync::mpsc::channel::<MacroOutput>(MACRO_QUEUE_CAP);
 
     let socket_file_path = env.fetch_runtime_socket_path();
-    let (cmd_tx, cmd_rx) = tokio::sync::mpsc::channel::<String>(IPC_QUEUE_CAP);
-    tokio::spawn(ipc_sender(cmd_rx, socket_file_path));
-    loop {
+    let (cmd_tx, cmd_rx) = tokio::sync::mpsc::channel::<ipc::Command>(IPC_QUEUE_CAP);
+    let ipc_handle =
+        tokio::spawn(ipc::sender(cmd_rx, socket_file_path, env.fetch_swhks_secret_path()));
+    let control_path = control::socket_path(&env.runtime_dir);
+    let (control_tx, mut control_rx) = tokio::sync::mpsc::channel(CONTROL_QUEUE_CAP);
+    if let Err(e) = control::listen(&control_path, env.uid, control_tx) {
+        log::warn!("Control socket unavailable at {}: {e}", control_path.display());
+    }
+    let exit_code = loop {
         select! {
             _ = &mut hotkey_repeat_timer, if repeat_timer_active(last_hotkey.as_ref()) => {
                 let hotkey = last_hotkey.clone().unwrap();
-                dispatch_hotkey(hotkey.clone(), &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut active_macro);
-                hotkey_repeat_timer.as_mut().reset(Instant::now() + Duration::from_millis(repeat_cooldown_duration));
+                let rate = hotkey.repeat.rate;
+                dispatch_hotkey(hotkey, &last_hotkey_device, &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut macros);
+                hotkey_repeat_timer.as_mut().reset(Instant::now() + Duration::from_millis(rate));
+            }
+
+            Some((request, reply_tx)) = control_rx.recv() => {
+                let reply = handle_control(&request, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut macros);
+                let _ = reply_tx.send(reply);
             }
 
             //not fully in macro due to `select!` limitations
-            Some(events) = macro_emit_next(
+            Some(output) = macro_emit_next(
                 #[cfg(feature = "macro")]
                 &mut macro_emit_rx,
             ) => {
-                emit_or_warn(&mut uinput_device, &events);
+                handle_macro_output(output, &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut macros);
             }
 
             Some(signal) = signals.next() => {
                 match signal {
                     SIGUSR1 => {
                         execution_is_paused = true;
+                        macros.stop_all();
+                        uinput_device.release_all();
                         for stream in device_stream_map.values_mut() {
                             let _ = stream.device_mut().ungrab();
                         }
@@ -334,32 +564,70 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     SIGUSR2 => {
                         execution_is_paused = false;
                         for stream in device_stream_map.values_mut() {
-                            let _ = stream.device_mut().grab();
+                            grab(stream.device_mut());
                         }
                     }
 
                     SIGHUP => {
+                        macros.stop_all();
+                        uinput_device.release_all();
                         config = load_config();
                         modes = config.modes;
                         default_mode = config.default_mode;
                         current_mode = config.default_mode;
+                        let switches = has_switch_hotkeys(&modes);
+                        // Switch-only devices follow the config, an explicit device list stays as it is.
+                        if switches != read_switches && arg_add_devices.is_empty() {
+                            if switches {
+                                for (path, mut device) in evdev::enumerate() {
+                                    let Some(node) = path.to_str() else { continue };
+                                    if device_stream_map.contains_key(node) || to_ignore(&device) || !check_device_is_supported(&device, true) {
+                                        continue;
+                                    }
+                                    let name = device.name().unwrap_or("[unknown]");
+                                    log::info!("Device '{name}' at '{node}' added for switch hotkeys.");
+                                    if !execution_is_paused {
+                                        grab(&mut device);
+                                    }
+                                    device_states.insert(node.to_string(), DeviceState::new(&device));
+                                    device_stream_map.insert(node.to_string(), device.into_event_stream()?);
+                                }
+                            } else {
+                                let unused: Vec<String> = device_stream_map
+                                    .iter()
+                                    .filter(|(_, stream)| !check_device_is_supported(stream.device(), false))
+                                    .map(|(node, _)| node.clone())
+                                    .collect();
+                                for node in unused {
+                                    device_states.remove(&node);
+                                    let stream = device_stream_map.remove(&node).expect("device not in stream_map");
+                                    let name = stream.device().name().unwrap_or("[unknown]");
+                                    log::info!("Device '{name}' at '{node}' dropped, no switch hotkeys left.");
+                                }
+                            }
+                        }
+                        read_switches = switches;
+                    }
+
+                    SIGTERM | SIGINT | SIGQUIT => {
+                        log::info!("Received signal {signal}, shutting down...");
+                        break 0;
                     }
 
-                    SIGINT => {
+                    // No time for an orderly shutdown, just don't leave the keyboard grabbed.
+                    SIGABRT | SIGBUS | SIGSYS | SIGTRAP | SIGXCPU | SIGXFSZ => {
+                        log::error!("Received fatal signal {signal}, exiting...");
                         for stream in device_stream_map.values_mut() {
                             let _ = stream.device_mut().ungrab();
                         }
-                        log::warn!("Received SIGINT signal, exiting...");
-                        exit(1);
+                        uinput_device.release_all();
+                        remove_pidfile(&env.runtime_dir);
+                        control::remove(&control_path);
+                        exit(128 + signal);
                     }
 
                     _ => {
-                        for stream in device_stream_map.values_mut() {
-                            let _ = stream.device_mut().ungrab();
-                        }
-                        log::warn!("Received signal: {signal:#?}");
-                        log::warn!("Exiting...");
-                        exit(1);
+                        log::debug!("Ignored signal: {signal}");
                     }
                 }
             }
@@ -388,10 +656,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             },
                             Ok(device) => device
                         };
-                        if !to_ignore(&device) && (to_add(&device) || check_device_is_supported(&device)) {
+                        if !to_ignore(&device) && (to_add(&device) || check_device_is_supported(&device, read_switches)) {
                             let name = device.name().unwrap_or("[unknown]");
                             log::info!("Device '{name}' at '{node}' added.");
-                            let _ = device.grab();
+                            grab(&mut device);
                             device_states.insert(node.to_string(), DeviceState::new(&device));
                             device_stream_map.insert(node.to_string(), device.into_event_stream()?);
                         }
@@ -402,6 +670,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             let stream = device_stream_map.remove(node).expect("device not in stream_map");
                             let name = stream.device().name().unwrap_or("[unknown]");
                             log::info!("Device '{name}' at '{node}' removed");
+                            // Its keys may still be held on the virtual device.
+                            uinput_device.release_all();
                         }
                     }
                     _ => {
@@ -412,11 +682,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
             Some((node, Ok(mut event))) = device_stream_map.next() => {
                 #[cfg(feature = "macro")]
-                if let Some(ref state) = active_macro && state.handle.is_finished() {
-                    active_macro = None;
-                }
+                macros.reap();
 
                 let device_state = &mut device_states.get_mut(&node).expect("device not in states map");
+                source_device.clone_from(&device_state.name);
                 let key = match event.destructure() {
                     EventSummary::Key(_, keycode, _) => {
                         match modes[current_mode].remaps.get(&keycode) {
@@ -427,33 +696,80 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             _ => keycode
                         }
                     },
-                    EventSummary::Switch(..) => {
-                        emit_or_warn(&mut uinput_switches_device, &[event]);
+                    EventSummary::Switch(_, switch, value) => {
+                        if !device_state.switch_only {
+                            emit_or_warn(&mut uinput_switches_device, &[event]);
+                        }
+                        if execution_is_paused {
+                            continue
+                        }
+                        let fired: Vec<Hotkey> = modes[current_mode]
+                            .switch_hotkeys
+                            .iter()
+                            .filter(|h| h.switch == switch && h.state == (value != 0))
+                            .map(|h| h.to_hotkey())
+                            .collect();
+                        for hotkey in fired {
+                            dispatch_hotkey(hotkey, &source_device, &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut macros);
+                        }
                         continue
                     }
-                    EventSummary::RelativeAxis(_, rlcode, _) => {
-                        if rel_mask::is_allowed(device_state.allowed_rel, rlcode) {
-                            emit_or_warn(&mut uinput_device, &[event]);
+                    EventSummary::RelativeAxis(_, rlcode, value) => {
+                        if !rel_mask::is_allowed(device_state.allowed_rel, rlcode) {
+                            continue
                         }
+                        #[cfg(feature = "macro")]
+                        if let Some(recording) = macros.recording.as_mut() && !execution_is_paused {
+                            recording.relative(rlcode, value);
+                        }
+                        if !execution_is_paused
+                            && let Some(direction) = wheel_direction(rlcode, value)
+                            && let Some(axis_hotkey) = modes[current_mode].axis_hotkeys.iter().find(|h| {
+                                h.direction == direction
+                                    && axis_hotkey_armed(h, &device_state.state_modifiers, device_state.state_modifiers_count)
+                            })
+                        {
+                            let hotkey = axis_hotkey.to_hotkey();
+                            let detents = rel_mask::wheel_detents(&mut device_state.hires_wheel, rlcode, value);
+                            if macros.hotkey_allowed(&hotkey.trigger) {
+                                for _ in 0..detents {
+                                    dispatch_hotkey(hotkey.clone(), &source_device, &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut macros);
+                                }
+                            }
+                            continue
+                        }
+                        uinput_device.emit(&[event]);
                         continue
                     }
                     _ => {
-                        emit_or_warn(&mut uinput_device, &[event]);
+                        uinput_device.emit(&[event]);
                         continue
                     }
                 };
                 log::debug!("Key: {key:#?}");
 
+                #[cfg(feature = "macro")]
+                for state in &macros.running {
+                    let _ = state.key_tx.try_send((key, event.value()));
+                }
+                #[cfg(feature = "macro")]
+                if let Some(recording) = macros.recording.as_mut() && !execution_is_paused {
+                    recording.key(key, event.value());
+                }
+
                 match event.value() {
                     // Key press
                     1 => {
                         #[cfg(feature = "macro")]
-                        if let Some(ref state) = active_macro
-                            && matches!(state.macro_type, config::MacroType::Endless)
-                            && key == KeyCode::KEY_ESC
                         {
-                            state.stop.store(true, Ordering::Relaxed);
-                            continue;
+                            let mut stopped_any = false;
+                            for state in macros.running.iter().filter(|state| state.stop_key == Some(key)) {
+                                state.stop.store(true, Ordering::Relaxed);
+                                stopped_any = true;
+                            }
+                            if stopped_any {
+                                continue;
+                            }
                         }
 
                         if config::ALLOWED_MODIFIERS.contains(&key) {
@@ -467,9 +783,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     // Key release
                     0 => {
                         #[cfg(feature = "macro")]
-                        if let Some(ref state) = active_macro
-                            && matches!(state.macro_type, config::MacroType::Hold)
-                        {
+                        for state in macros.running.iter().filter(|state| matches!(state.macro_type, config::MacroType::Hold)) {
                             let is_trigger_key = key == state.trigger_keybind.keysym
                                 || state.trigger_keybind.modifiers.contains(&key);
                             if is_trigger_key {
@@ -479,7 +793,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
                         if last_hotkey.is_some() && pending_release {
                             pending_release = false;
-                            dispatch_hotkey(last_hotkey.clone().unwrap(), &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut active_macro);
+                            dispatch_hotkey(last_hotkey.clone().unwrap(), &last_hotkey_device, &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut macros);
                             last_hotkey = None;
                         }
                         if config::ALLOWED_MODIFIERS.contains(&key) {
@@ -524,14 +838,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 if !modes[current_mode].options.swallow
                     // Don't emit event to virtual device if it's from a valid hotkey
                     && !event_in_hotkeys
-                    // Don't forward keys to virtual device when macro is running.
+                    // Don't forward keys to virtual device when a macro without `passthrough=#true` is running.
                     // Needed because otherwise macro keys get interupted by our keys even when they are part of shortcut
-                    && active_macro.is_none()
+                    && !macros.passthrough_blocked()
                 {
-                    emit_or_warn(&mut uinput_device, &[event]);
+                    uinput_device.emit(&[event]);
                 }
 
-                if execution_is_paused || !any_possible || last_hotkey.is_some() || active_macro.is_some() {
+                if execution_is_paused || !any_possible || last_hotkey.is_some() {
                     continue;
                 }
 
@@ -543,6 +857,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     .iter()
                     .filter(|hotkey| hotkey.keybind.modifiers.len() == device_state.state_modifiers_count)
                 {
+                    if !macros.hotkey_allowed(&hotkey.trigger) {
+                        continue;
+                    }
                     if hotkey_armed(
                         hotkey,
                         &device_state.state_modifiers,
@@ -550,52 +867,125 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         device_state.state_modifiers_count,
                     ) {
                         last_hotkey = Some(hotkey.clone());
+                        last_hotkey_device.clone_from(&source_device);
                         if pending_release { break; }
                         if hotkey.is_on_release() {
                             pending_release = true;
                             break;
                         }
-                        dispatch_hotkey(hotkey.clone(), &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut active_macro);
-                        hotkey_repeat_timer.as_mut().reset(Instant::now() + Duration::from_millis(repeat_cooldown_duration));
+                        dispatch_hotkey(hotkey.clone(), &source_device, &cmd_tx, &modes, &mut current_mode, default_mode, &mut uinput_device, #[cfg(feature = "macro")] &macro_emit_tx, &mut macros);
+                        hotkey_repeat_timer.as_mut().reset(Instant::now() + Duration::from_millis(hotkey.repeat.delay));
                         continue;
                     }
                 }
             }
         }
+    };
+
+    #[cfg(feature = "macro")]
+    {
+        macros.stop_all();
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_GRACE_MS);
+        while !macros.running.is_empty() {
+            match tokio::time::timeout_at(deadline, macro_emit_rx.recv()).await {
+                Ok(Some(MacroOutput::Events(events))) => uinput_device.emit_macro(&events),
+                Ok(Some(MacroOutput::Finished(id))) => macros.running.retain(|m| m.id != id),
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break,
+            }
+        }
+    }
+    uinput_device.release_all();
+    for stream in device_stream_map.values_mut() {
+        let _ = stream.device_mut().ungrab();
     }
-}
 
-async fn socket_write(command: &str, socket_path: PathBuf) -> Result<(), Box<dyn Error>> {
-    let mut stream = UnixStream::connect(socket_path).await?;
-    stream.write_all(command.as_bytes()).await?;
-    Ok(())
+    // The sender finishes once the queue is empty and every handle to it is gone.
+    drop(cmd_tx);
+    if tokio::time::timeout(Duration::from_millis(SHUTDOWN_GRACE_MS), ipc_handle).await.is_err() {
+        log::warn!("Gave up delivering queued commands to swhks");
+    }
+
+    remove_pidfile(&env.runtime_dir);
+    control::remove(&control_path);
+    exit(exit_code);
 }
 
-// Sends que to swhks one at a time,
-async fn ipc_sender(mut rx: tokio::sync::mpsc::Receiver<String>, socket_path: PathBuf) {
-    while let Some(cmd) = rx.recv().await {
-        if let Err(e) = socket_write(&cmd, socket_path.clone()).await {
-            log::error!("Failed to send command to swhks through IPC.");
-            log::error!("Please make sure that swhks is running.");
-            log::error!("Err: {e:#?}");
-        }
+// Only removes the file if it still belongs to this process.
+fn remove_pidfile(runtime_dir: &Path) {
+    let pidfile = runtime_dir.join("swhkdp.pid");
+    if fs::read_to_string(&pidfile).is_ok_and(|pid| pid.trim() == id().to_string())
+        && let Err(e) = fs::remove_file(&pidfile)
+    {
+        log::warn!("Unable to remove {}: {e}", pidfile.display());
     }
-    log::debug!("IPC sender stopped (all senders dropped).");
 }
 
 // `select!` workaround
 #[cfg(feature = "macro")]
-async fn macro_emit_next(
-    rx: &mut tokio::sync::mpsc::Receiver<Vec<evdev::InputEvent>>,
-) -> Option<Vec<evdev::InputEvent>> {
+async fn macro_emit_next(rx: &mut tokio::sync::mpsc::Receiver<MacroOutput>) -> Option<MacroOutput> {
     rx.recv().await
 }
 
 #[cfg(not(feature = "macro"))]
-async fn macro_emit_next() -> Option<Vec<evdev::InputEvent>> {
+async fn macro_emit_next() -> Option<MacroOutput> {
     std::future::pending().await
 }
 
+#[cfg(feature = "macro")]
+#[allow(clippy::too_many_arguments)]
+fn handle_macro_output(
+    output: MacroOutput,
+    cmd_tx: &tokio::sync::mpsc::Sender<ipc::Command>,
+    modes: &[config::Mode],
+    current_mode: &mut usize,
+    default_mode: usize,
+    uinput: &mut VirtualKeyboard,
+    macro_emit_tx: &tokio::sync::mpsc::Sender<MacroOutput>,
+    macros: &mut MacroSet,
+) {
+    match output {
+        MacroOutput::Events(events) => uinput.emit_macro(&events),
+        MacroOutput::Exec(command) => send_command(
+            cmd_tx,
+            shell_command(
+                command,
+                &modes[*current_mode],
+                "",
+                "",
+                &config::CommandOptions::default(),
+            ),
+        ),
+        MacroOutput::EnterMode(mode) => {
+            enter_mode(&mode, modes, current_mode, default_mode, uinput)
+        }
+        MacroOutput::Finished(id) => {
+            macros.running.retain(|m| m.id != id);
+            let queued = std::mem::take(&mut macros.queued);
+            for (keybind, trigger, macro_def) in queued {
+                if macros.is_running(&trigger) {
+                    macros.queued.push_back((keybind, trigger, macro_def));
+                } else {
+                    start_macro(keybind, trigger, macro_def, uinput, macro_emit_tx, macros);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "macro"))]
+fn handle_macro_output(
+    output: MacroOutput,
+    _: &tokio::sync::mpsc::Sender<ipc::Command>,
+    _: &[config::Mode],
+    _: &mut usize,
+    _: usize,
+    _: &mut VirtualKeyboard,
+    _: &mut MacroSet,
+) {
+    match output {}
+}
+
 // Had to deal with a few glitchy devices, fail to write shouldn't be fatal. Just logged
 // and dropped to keep daemon alive instead of abort.
 fn emit_or_warn(dev: &mut evdev::uinput::VirtualDevice, events: &[evdev::InputEvent]) {
@@ -612,12 +1002,33 @@ pub fn check_pkexec() {
     }
 }
 
-pub fn check_device_is_supported(device: &Device) -> bool {
-    if device.supported_events().contains(evdev::EventType::KEY)
+fn is_keyboard(device: &Device) -> bool {
+    device.supported_events().contains(evdev::EventType::KEY)
         && !device.supported_events().contains(evdev::EventType::FORCEFEEDBACK)
         && !device.supported_keys().is_some_and(|keys| keys.contains(KeyCode::BTN_TOUCH))
-    {
-        if device.name() == Some("swhkdp virtual output") {
+}
+
+fn is_switch_only(device: &Device) -> bool {
+    device.supported_events().contains(evdev::EventType::SWITCH) && !is_keyboard(device)
+}
+
+// Switch-only devices (lid, tablet mode) are never grabbed, logind and the compositor keep
+// reading them too, e.g. for `HandleLidSwitch`.
+fn grab(device: &mut Device) {
+    if !is_switch_only(device) {
+        let _ = device.grab();
+    }
+}
+
+// Switch-only devices are read only when some mode binds a switch.
+pub fn check_device_is_supported(device: &Device, read_switches: bool) -> bool {
+    let is_keyboard = is_keyboard(device);
+    let is_switch = read_switches && device.supported_events().contains(evdev::EventType::SWITCH);
+    if is_keyboard || is_switch {
+        if matches!(
+            device.name(),
+            Some("swhkdp virtual output") | Some("swhkdp switches virtual output")
+        ) {
             return false;
         }
         log::debug!("Device: {}", device.name().unwrap_or("Unknown device name"),);
@@ -775,26 +1186,147 @@ fn event_consumed(
         && !hotkey.is_send()
 }
 
+fn has_switch_hotkeys(modes: &[config::Mode]) -> bool {
+    modes.iter().any(|mode| !mode.switch_hotkeys.is_empty())
+}
+
+fn axis_hotkey_armed(
+    hotkey: &config::AxisHotkey,
+    state_modifiers: &AttributeSet<KeyCode>,
+    state_modifiers_count: usize,
+) -> bool {
+    hotkey.modifiers.len() == state_modifiers_count
+        && state_modifiers.iter().all(|m| hotkey.modifiers.contains(&m))
+}
+
+fn wheel_direction(code: RelativeAxisCode, value: i32) -> Option<config::WheelDirection> {
+    use config::WheelDirection::*;
+    match code {
+        RelativeAxisCode::REL_WHEEL | RelativeAxisCode::REL_WHEEL_HI_RES => match value.signum() {
+            1 => Some(Up),
+            -1 => Some(Down),
+            _ => None,
+        },
+        RelativeAxisCode::REL_HWHEEL | RelativeAxisCode::REL_HWHEEL_HI_RES => {
+            match value.signum() {
+                1 => Some(Right),
+                -1 => Some(Left),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Toggle macros and `instance="toggle"` commands don't repeat, every dispatch would flip them.
 fn repeat_timer_active(last_hotkey: Option<&config::Hotkey>) -> bool {
-    last_hotkey.is_some_and(|hotkey| !hotkey.keybind.on_release)
+    last_hotkey.is_some_and(|hotkey| {
+        hotkey.repeat.enabled
+            && !hotkey.keybind.on_release
+            && hotkey.command.instance != config::Instance::Toggle
+            && !is_toggle_macro(hotkey)
+    })
+}
+
+#[cfg(feature = "macro")]
+fn is_toggle_macro(hotkey: &config::Hotkey) -> bool {
+    matches!(&hotkey.action, config::HotkeyAction::Macro(m) if matches!(m.macro_type, config::MacroType::Toggle))
+}
+
+#[cfg(not(feature = "macro"))]
+fn is_toggle_macro(_: &config::Hotkey) -> bool {
+    false
+}
+
+fn enter_mode(
+    name: &str,
+    modes: &[config::Mode],
+    current_mode: &mut usize,
+    default_mode: usize,
+    uinput: &mut VirtualKeyboard,
+) {
+    // Keys pressed under the old mode's remaps would never see their release otherwise.
+    uinput.release_for_mode_switch();
+    if name == "default" {
+        *current_mode = default_mode;
+        log::info!("Switching to default mode: {}", modes[*current_mode].name);
+    } else if let Some(i) = modes.iter().position(|mode| mode.name == name) {
+        *current_mode = i;
+        log::info!("Switching to mode: {}", modes[*current_mode].name);
+    } else {
+        log::warn!("Mode not found: {name}");
+    }
 }
 
-#[cfg_attr(feature = "macro", allow(clippy::too_many_arguments))]
+fn send_command(cmd_tx: &tokio::sync::mpsc::Sender<ipc::Command>, command: ipc::Command) {
+    match cmd_tx.try_send(command) {
+        Ok(()) => {}
+        Err(tokio::sync::mpsc::error::TrySendError::Full(cmd)) => {
+            log::warn!("swhks command queue full ({IPC_QUEUE_CAP}); dropping: {:?}", cmd.command);
+        }
+        Err(tokio::sync::mpsc::error::TrySendError::Closed(cmd)) => {
+            log::error!("swhks command queue closed; dropping: {:?}", cmd.command);
+        }
+    }
+}
+
+// Hotkey `env` entries come last so they can override the `SWHKDP_*` variables.
+fn shell_command(
+    command: String,
+    mode: &config::Mode,
+    trigger: &str,
+    device: &str,
+    options: &config::CommandOptions,
+) -> ipc::Command {
+    let mut env = vec![
+        ("SWHKDP_MODE".to_string(), mode.name.clone()),
+        ("SWHKDP_KEY".to_string(), trigger.to_string()),
+        ("SWHKDP_DEVICE".to_string(), device.to_string()),
+    ];
+    env.extend(options.env.iter().cloned());
+    ipc::Command {
+        command,
+        trigger: trigger.to_string(),
+        env,
+        instance: options.instance,
+        timeout: options.timeout,
+        shell: mode.options.shell.clone(),
+        cwd: options.cwd.clone(),
+    }
+}
+
+// Returns to the default mode after a hotkey in a `oneoff` mode, returns the mode it fired in.
+fn leave_oneoff_mode(
+    modes: &[config::Mode],
+    current_mode: &mut usize,
+    default_mode: usize,
+) -> usize {
+    let fired_mode = *current_mode;
+    if modes[fired_mode].options.oneoff {
+        *current_mode = default_mode;
+    }
+    fired_mode
+}
+
+#[allow(clippy::too_many_arguments)]
 fn dispatch_hotkey(
     hotkey: Hotkey,
-    cmd_tx: &tokio::sync::mpsc::Sender<String>,
+    device: &str,
+    cmd_tx: &tokio::sync::mpsc::Sender<ipc::Command>,
     modes: &[config::Mode],
     current_mode: &mut usize,
     default_mode: usize,
-    uinput: &mut evdev::uinput::VirtualDevice,
-    #[cfg(feature = "macro")] macro_emit_tx: &tokio::sync::mpsc::Sender<Vec<evdev::InputEvent>>,
-    active_macro: &mut Option<MacroState>,
+    uinput: &mut VirtualKeyboard,
+    #[cfg(feature = "macro")] macro_emit_tx: &tokio::sync::mpsc::Sender<MacroOutput>,
+    macros: &mut MacroSet,
 ) {
     log::info!("Hotkey pressed: {hotkey:#?}");
     #[cfg(not(feature = "macro"))]
-    let _ = (active_macro, uinput);
-    if modes[*current_mode].options.oneoff {
-        *current_mode = default_mode;
+    let _ = macros;
+    // Commands see the mode the hotkey fired in, even when it's left right away.
+    let fired_mode = leave_oneoff_mode(modes, current_mode, default_mode);
+    if fired_mode != *current_mode {
+        uinput.release_for_mode_switch();
     }
 
     match hotkey.action {
@@ -806,28 +1338,31 @@ fn dispatch_hotkey(
                     let mut words = cmd.split_whitespace();
                     match words.next().unwrap() {
                         config::MODE_ENTER_STATEMENT => {
-                            let enter_mode = cmd.split(' ').nth(1).unwrap();
-                            if enter_mode == "default" {
-                                *current_mode = default_mode;
-                                log::info!(
-                                    "Switching to default mode: {}",
-                                    modes[*current_mode].name
-                                );
-                            } else {
-                                let mut found = false;
-                                for (i, mode) in modes.iter().enumerate() {
-                                    if mode.name == enter_mode {
-                                        *current_mode = i;
-                                        found = true;
-                                        break;
-                                    }
-                                }
-                                if found {
-                                    log::info!("Switching to mode: {}", modes[*current_mode].name);
-                                } else {
-                                    log::warn!("Mode not found: {enter_mode}");
-                                }
-                            }
+                            let mode = cmd.split(' ').nth(1).unwrap();
+                            enter_mode(mode, modes, current_mode, default_mode, uinput);
+                        }
+                        #[cfg(feature = "macro")]
+                        config::RECORD_STATEMENT => toggle_recording(words.next(), macros),
+                        #[cfg(feature = "macro")]
+                        config::PLAY_STATEMENT => {
+                            let Some(macro_def) =
+                                words.next().and_then(|slot| macros.slots.get(slot))
+                            else {
+                                log::warn!("Nothing recorded for {cmd:?}");
+                                continue;
+                            };
+                            launch_macro(
+                                hotkey.keybind.clone(),
+                                hotkey.trigger.clone(),
+                                macro_def.clone(),
+                                uinput,
+                                macro_emit_tx,
+                                macros,
+                            );
+                        }
+                        #[cfg(not(feature = "macro"))]
+                        config::RECORD_STATEMENT | config::PLAY_STATEMENT => {
+                            log::warn!("{cmd:?} ignored (macro feature not enabled)");
                         }
                         _ => commands_to_send.push_str(format!("{cmd} &&").as_str()),
                     }
@@ -839,52 +1374,198 @@ fn dispatch_hotkey(
                 commands_to_send = commands_to_send.strip_suffix(" &&").unwrap().to_string();
             }
             if !commands_to_send.is_empty() {
-                match cmd_tx.try_send(commands_to_send) {
-                    Ok(()) => {}
-                    Err(tokio::sync::mpsc::error::TrySendError::Full(cmd)) => {
-                        log::warn!("swhks command queue full ({IPC_QUEUE_CAP}); dropping: {cmd:?}");
-                    }
-                    Err(tokio::sync::mpsc::error::TrySendError::Closed(cmd)) => {
-                        log::error!("swhks command queue closed; dropping: {cmd:?}");
-                    }
-                }
+                send_command(
+                    cmd_tx,
+                    shell_command(
+                        commands_to_send,
+                        &modes[fired_mode],
+                        &hotkey.trigger,
+                        device,
+                        &hotkey.command,
+                    ),
+                );
             }
         }
 
         #[cfg(feature = "macro")]
         config::HotkeyAction::Macro(macro_def) => {
-            if let Some(state) = active_macro.as_ref() {
-                state.stop.store(true, Ordering::Relaxed);
-            }
+            launch_macro(hotkey.keybind, hotkey.trigger, macro_def, uinput, macro_emit_tx, macros);
+        }
+    }
+}
 
-            for &modifier in &hotkey.keybind.modifiers {
-                emit_or_warn(
-                    uinput,
-                    &[evdev::InputEvent::new(evdev::EventType::KEY.0, modifier.0, 0)],
-                );
-            }
-            if hotkey.keybind.send {
-                emit_or_warn(
-                    uinput,
-                    &[evdev::InputEvent::new(evdev::EventType::KEY.0, hotkey.keybind.keysym.0, 0)],
-                );
-            }
+// `@record <slot>` starts capturing, any `@record` while capturing stores the result.
+#[cfg(feature = "macro")]
+fn toggle_recording(slot: Option<&str>, macros: &mut MacroSet) {
+    if macros.recording.is_some() {
+        stop_recording(macros);
+        return;
+    }
+    match slot {
+        Some(slot) => start_recording(slot, macros),
+        None => log::warn!("{} needs a slot name", config::RECORD_STATEMENT),
+    }
+}
 
-            let macro_type = macro_def.macro_type;
-            let trigger_keybind = hotkey.keybind.clone();
-            let stop = Arc::new(AtomicBool::new(false));
-            let stop_clone = stop.clone();
-            let emit_tx = macro_emit_tx.clone();
+#[cfg(feature = "macro")]
+fn start_recording(slot: &str, macros: &mut MacroSet) {
+    log::info!("Recording macro into slot {slot:?}");
+    macros.recording = Some(recorder::Recorder::new(slot.to_string()));
+}
 
-            let handle = tokio::spawn(async move {
-                macro_runner::run_macro(macro_def, emit_tx, stop_clone).await;
-            });
+// Stores what was recorded in its slot, unless nothing was, and returns it as KDL.
+#[cfg(feature = "macro")]
+fn stop_recording(macros: &mut MacroSet) -> Option<String> {
+    let recording = macros.recording.take()?;
+    let slot = recording.slot.clone();
+    let steps = recording.finish();
+    let kdl = recorder::to_kdl(&slot, &steps);
+    log::info!("Recorded {} step(s):\n{kdl}", steps.len());
+    if !steps.is_empty() {
+        let macro_def = config::MacroDef {
+            macro_type: config::MacroType::Simple,
+            steps,
+            key_delay: 0,
+            stop_key: None,
+            policy: config::MacroPolicy::default(),
+            allow_hotkeys: false,
+            allow_passthrough: false,
+            speed: 1.0,
+            step_interval: config::DEFAULT_STEP_INTERVAL_MS,
+        };
+        macros.slots.insert(slot, macro_def);
+    }
+    Some(kdl)
+}
 
-            *active_macro = Some(MacroState { stop, handle, macro_type, trigger_keybind });
+// Requests from the control socket, see `control.rs`. Replies end with a newline.
+#[cfg(feature = "macro")]
+fn handle_control(
+    request: &str,
+    uinput: &mut VirtualKeyboard,
+    macro_emit_tx: &tokio::sync::mpsc::Sender<MacroOutput>,
+    macros: &mut MacroSet,
+) -> String {
+    let mut words = request.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("record"), Some(slot), None) => match &macros.recording {
+            Some(recording) => format!("Already recording into slot {:?}\n", recording.slot),
+            None => {
+                start_recording(slot, macros);
+                format!("Recording into slot {slot:?}\n")
+            }
+        },
+        (Some("stop"), None, None) => match stop_recording(macros) {
+            Some(kdl) => format!("{kdl}\n"),
+            None => "Not recording\n".to_string(),
+        },
+        (Some("play"), Some(slot), None) => {
+            let Some(macro_def) = macros.slots.get(slot) else {
+                return format!("Nothing recorded for slot {slot:?}\n");
+            };
+            let keybind = config::KeyBinding {
+                keysym: KeyCode::KEY_RESERVED,
+                modifiers: HashSet::new(),
+                send: false,
+                on_release: false,
+            };
+            let trigger = format!("{} {slot}", config::PLAY_STATEMENT);
+            launch_macro(keybind, trigger, macro_def.clone(), uinput, macro_emit_tx, macros);
+            format!("Playing slot {slot:?}\n")
         }
+        (Some("show"), Some(slot), None) => match macros.slots.get(slot) {
+            Some(macro_def) => format!("{}\n", recorder::to_kdl(slot, &macro_def.steps)),
+            None => format!("Nothing recorded for slot {slot:?}\n"),
+        },
+        _ => format!(
+            "Unknown command {request:?}, expected record <slot>, stop, play <slot> or show <slot>\n"
+        ),
     }
 }
 
+#[cfg(not(feature = "macro"))]
+fn handle_control(_: &str, _: &mut VirtualKeyboard, _: &mut MacroSet) -> String {
+    "Recording needs swhkdp built with the macro feature\n".to_string()
+}
+
+#[cfg(feature = "macro")]
+fn launch_macro(
+    keybind: config::KeyBinding,
+    trigger: String,
+    macro_def: config::MacroDef,
+    uinput: &mut VirtualKeyboard,
+    macro_emit_tx: &tokio::sync::mpsc::Sender<MacroOutput>,
+    macros: &mut MacroSet,
+) {
+    if macros.stop_toggle(&trigger) {
+        return;
+    }
+    let running = macros.is_running(&trigger);
+    match macro_def.policy {
+        config::MacroPolicy::Replace => macros.stop_trigger(&trigger),
+        config::MacroPolicy::Ignore if running => {
+            log::debug!("Macro {trigger} already running; ignoring trigger");
+            return;
+        }
+        config::MacroPolicy::Queue if running => {
+            if macros.queued.len() >= MACRO_QUEUE_CAP {
+                log::warn!("Macro queue full ({MACRO_QUEUE_CAP}); dropping {trigger}");
+            } else {
+                macros.queued.push_back((keybind, trigger, macro_def));
+            }
+            return;
+        }
+        _ => {}
+    }
+    start_macro(keybind, trigger, macro_def, uinput, macro_emit_tx, macros);
+}
+
+#[cfg(feature = "macro")]
+fn start_macro(
+    keybind: config::KeyBinding,
+    trigger: String,
+    macro_def: config::MacroDef,
+    uinput: &mut VirtualKeyboard,
+    macro_emit_tx: &tokio::sync::mpsc::Sender<MacroOutput>,
+    macros: &mut MacroSet,
+) {
+    for &modifier in &keybind.modifiers {
+        uinput.emit(&[evdev::InputEvent::new(evdev::EventType::KEY.0, modifier.0, 0)]);
+    }
+    if keybind.send {
+        uinput.emit(&[evdev::InputEvent::new(evdev::EventType::KEY.0, keybind.keysym.0, 0)]);
+    }
+
+    let id = macros.next_id;
+    macros.next_id += 1;
+    let macro_type = macro_def.macro_type;
+    let stop_key = macro_def.stop_key;
+    let allow_hotkeys = macro_def.allow_hotkeys;
+    let allow_passthrough = macro_def.allow_passthrough;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_clone = stop.clone();
+    let emit_tx = macro_emit_tx.clone();
+    let (key_tx, key_rx) = tokio::sync::mpsc::channel(MACRO_QUEUE_CAP);
+
+    let handle = tokio::spawn(async move {
+        macro_runner::run_macro(macro_def, emit_tx.clone(), key_rx, stop_clone).await;
+        let _ = emit_tx.send(MacroOutput::Finished(id)).await;
+    });
+
+    macros.running.push(MacroState {
+        id,
+        trigger,
+        stop,
+        handle,
+        macro_type,
+        trigger_keybind: keybind,
+        stop_key,
+        allow_hotkeys,
+        allow_passthrough,
+        key_tx,
+    });
+}
+
 fn run_verify_mode(config_file_path: &Path) -> Result<(), Box<dyn Error>> {
     if !config_file_path.exists() {
         log::info!("Error: Config file not found at: {}", config_file_path.display());
@@ -897,10 +1578,12 @@ fn run_verify_mode(config_file_path: &Path) -> Result<(), Box<dyn Error>> {
             for (i, mode) in cfg.modes.iter().enumerate() {
                 let default_marker = if i == cfg.default_mode { " (default)" } else { "" };
                 log::info!(
-                    "  - {}{}: {} hotkeys, {} remaps",
+                    "  - {}{}: {} hotkeys, {} wheel hotkeys, {} switch hotkeys, {} remaps",
                     mode.name,
                     default_marker,
                     mode.hotkeys.len(),
+                    mode.axis_hotkeys.len(),
+                    mode.switch_hotkeys.len(),
                     mode.remaps.len(),
                 );
             }
@@ -926,7 +1609,7 @@ async fn run_watch_mode(
     let supported_devices: Vec<(PathBuf, Device)> = {
         if arg_add_devices.is_empty() {
             evdev::enumerate()
-                .filter(|(_, dev)| !to_ignore(dev) && check_device_is_supported(dev))
+                .filter(|(_, dev)| !to_ignore(dev) && check_device_is_supported(dev, false))
                 .collect()
         } else {
             evdev::enumerate().filter(|(_, dev)| !to_ignore(dev) && to_add(dev)).collect()
@@ -991,7 +1674,7 @@ async fn run_watch_mode(
                             },
                             Ok(device) => device
                         };
-                        if !to_ignore(&device) && (to_add(&device) || check_device_is_supported(&device)) {
+                        if !to_ignore(&device) && (to_add(&device) || check_device_is_supported(&device, false)) {
                             let name = device.name().unwrap_or("[unknown]");
                             log::info!("Watch mode: device '{name}' at '{node}' added.");
                             device_stream_map.insert(node.to_string(), device.into_event_stream()?);
@@ -1016,3 +1699,29 @@ async fn run_watch_mode(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oneoff_commands_see_the_mode_they_fired_in() {
+        let config = config::load_from_str(
+            "general {\n  oneoff #true\n}\nmaster {\n  KEY_1 \"@enter other\"\n}\nother {\n  KEY_2 \"true\"\n}\n",
+        )
+        .unwrap();
+        let modes = &config.modes;
+        let mut current_mode = modes.iter().position(|m| m.name == "other").unwrap();
+        let fired_mode = leave_oneoff_mode(modes, &mut current_mode, config.default_mode);
+        assert_eq!(current_mode, config.default_mode);
+        let hotkey = &modes[fired_mode].hotkeys[0];
+        let command = shell_command(
+            "true".to_string(),
+            &modes[fired_mode],
+            &hotkey.trigger,
+            "kbd",
+            &hotkey.command,
+        );
+        assert!(command.env.contains(&("SWHKDP_MODE".to_string(), "other".to_string())));
+    }
+}
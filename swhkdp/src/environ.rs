@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 pub struct Env {
     pub runtime_dir: PathBuf,
+    /// The user swhkdp was started for.
+    pub uid: u32,
 }
 
 impl Env {
@@ -18,13 +20,17 @@ impl Env {
         };
         let runtime_dir = PathBuf::from(format!("/run/user/{pkexec_id}"));
 
-        Self { runtime_dir }
+        Self { runtime_dir, uid: pkexec_id }
     }
 
     pub fn fetch_runtime_socket_path(&self) -> PathBuf {
         self.runtime_dir.join("swhkdp.sock")
     }
 
+    pub fn fetch_swhks_secret_path(&self) -> PathBuf {
+        self.runtime_dir.join("swhks.secret")
+    }
+
     pub fn pkexec_err() -> ! {
         log::error!("Failed to launch swhkdp!!!");
         log::error!("Make sure to launch the binary with pkexec.");
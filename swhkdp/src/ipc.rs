@@ -0,0 +1,416 @@
+//! Connection to swhks.
+//!
+//! Both directions use the same framing: a big-endian `u32` payload length, then the payload.
+//! A payload starts with the protocol version and a message kind byte. Integers are big-endian,
+//! strings are a `u32` length followed by UTF-8 bytes. swhks keeps its own copy of this format
+//! in `swhks/src/ipc.rs`, the two must change together.
+
+use crate::config::Instance;
+use nix::fcntl::OFlag;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc::Receiver;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// swhkdp -> swhks
+const KIND_RUN: u8 = 1;
+const KIND_HELLO: u8 = 4;
+// swhks -> swhkdp
+const KIND_SPAWNED: u8 = 2;
+const KIND_EXITED: u8 = 3;
+const KIND_SKIPPED: u8 = 5;
+const KIND_QUEUED: u8 = 6;
+const KIND_REJECTED: u8 = 7;
+
+const MAX_FRAME_LEN: u32 = 1 << 20;
+const MAX_SECRET_LEN: u64 = 128;
+
+/// A shell command for swhks to run as the user.
+#[derive(Debug)]
+pub struct Command {
+    pub command: String,
+    /// Hotkey that fired it, empty when a macro `exec` step ran it.
+    pub trigger: String,
+    /// Extra environment variables for the command.
+    pub env: Vec<(String, String)>,
+    pub instance: Instance,
+    /// Milliseconds before swhks kills the command, `None` lets it run forever.
+    pub timeout: Option<u32>,
+    /// Program and arguments the command is appended to.
+    pub shell: Vec<String>,
+    pub cwd: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExitStatus {
+    Code(i32),
+    Signal(i32),
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Code(code) => write!(f, "exit code {code}"),
+            ExitStatus::Signal(signal) => write!(f, "signal {signal}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    /// The command was started, or could not be and `error` says why.
+    Spawned {
+        id: u64,
+        error: Option<String>,
+    },
+    Exited {
+        id: u64,
+        status: ExitStatus,
+    },
+    /// The command was deliberately not started, e.g. because of its `instance` option.
+    Skipped {
+        id: u64,
+        reason: String,
+    },
+    /// The command waits for swhks's limits to allow it, `Spawned` follows once it starts.
+    Queued {
+        id: u64,
+        reason: String,
+    },
+    /// swhks didn't accept the secret and closes the connection, nothing sent on it was run.
+    Rejected {
+        reason: String,
+    },
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn encode_hello(secret: &str) -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION, KIND_HELLO];
+    put_str(&mut payload, secret);
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+// The file lives in a directory the user controls while we run as root, so refuse anything
+// that isn't a small, user-owned regular file of hex digits instead of following it.
+fn read_secret(secret_path: &Path) -> Option<String> {
+    let owner = secret_path.parent().and_then(|dir| fs::metadata(dir).ok())?.uid();
+    let mut file = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NOFOLLOW.bits())
+        .open(secret_path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            log::warn!("Ignoring {}: {e}", secret_path.display());
+            return None;
+        }
+    };
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() || metadata.uid() != owner || metadata.len() > MAX_SECRET_LEN {
+        log::warn!("Ignoring {}: not a secret written by swhks", secret_path.display());
+        return None;
+    }
+    let mut secret = String::new();
+    file.read_to_string(&mut secret).ok()?;
+    if secret.is_empty() || !secret.bytes().all(|b| b.is_ascii_hexdigit()) {
+        log::warn!("Ignoring {}: not a secret written by swhks", secret_path.display());
+        return None;
+    }
+    Some(secret)
+}
+
+pub fn encode_run(id: u64, command: &Command) -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION, KIND_RUN];
+    payload.extend_from_slice(&id.to_be_bytes());
+    put_str(&mut payload, &command.command);
+    put_str(&mut payload, &command.trigger);
+    payload.extend_from_slice(&(command.env.len() as u32).to_be_bytes());
+    for (key, value) in &command.env {
+        put_str(&mut payload, key);
+        put_str(&mut payload, value);
+    }
+    payload.push(match command.instance {
+        Instance::Multiple => 0,
+        Instance::Single => 1,
+        Instance::Toggle => 2,
+    });
+    payload.extend_from_slice(&command.timeout.unwrap_or(0).to_be_bytes());
+    payload.extend_from_slice(&(command.shell.len() as u32).to_be_bytes());
+    for arg in &command.shell {
+        put_str(&mut payload, arg);
+    }
+    put_str(&mut payload, command.cwd.as_deref().unwrap_or_default());
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        if self.0.len() < n {
+            return Err("truncated message".to_string());
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+pub fn decode_reply(payload: &[u8]) -> Result<Reply, String> {
+    let mut d = Decoder(payload);
+    let version = d.u8()?;
+    if version != PROTOCOL_VERSION {
+        return Err(format!("unsupported protocol version {version}"));
+    }
+    match d.u8()? {
+        KIND_SPAWNED => {
+            let id = d.u64()?;
+            let error = match d.u8()? {
+                0 => None,
+                _ => Some(d.string()?),
+            };
+            Ok(Reply::Spawned { id, error })
+        }
+        KIND_EXITED => {
+            let id = d.u64()?;
+            let status = match d.u8()? {
+                0 => ExitStatus::Code(d.i32()?),
+                _ => ExitStatus::Signal(d.i32()?),
+            };
+            Ok(Reply::Exited { id, status })
+        }
+        KIND_SKIPPED => Ok(Reply::Skipped { id: d.u64()?, reason: d.string()? }),
+        KIND_QUEUED => Ok(Reply::Queued { id: d.u64()?, reason: d.string()? }),
+        KIND_REJECTED => Ok(Reply::Rejected { reason: d.string()? }),
+        kind => Err(format!("unknown message kind {kind}")),
+    }
+}
+
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32().await?;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {len} bytes")));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+type Connection = (OwnedReadHalf, OwnedWriteHalf);
+
+async fn connect(socket_path: &Path, secret_path: &Path) -> io::Result<Connection> {
+    let (reader, mut writer) = UnixStream::connect(socket_path).await?.into_split();
+    // swhks only writes the secret when it requires one, so present it whenever it's there.
+    if let Some(secret) = read_secret(secret_path) {
+        writer.write_all(&encode_hello(&secret)).await?;
+    }
+    Ok((reader, writer))
+}
+
+// Reconnects once if the connection went stale, e.g. because swhks was restarted.
+async fn send_frame(
+    conn: &mut Option<Connection>,
+    socket_path: &Path,
+    secret_path: &Path,
+    frame: &[u8],
+) -> bool {
+    for _ in 0..2 {
+        if conn.is_none() {
+            match connect(socket_path, secret_path).await {
+                Ok(connection) => *conn = Some(connection),
+                Err(e) => {
+                    log::error!("Failed to send command to swhks through IPC.");
+                    log::error!("Please make sure that swhks is running.");
+                    log::error!("Err: {e:#?}");
+                    return false;
+                }
+            }
+        }
+        let (_, writer) = conn.as_mut().unwrap();
+        match writer.write_all(frame).await {
+            Ok(()) => return true,
+            Err(e) => {
+                log::debug!("swhks connection lost ({e}), reconnecting");
+                *conn = None;
+            }
+        }
+    }
+    false
+}
+
+// `select!` workaround, only polled while connected.
+async fn next_reply(conn: &mut Option<Connection>) -> io::Result<Vec<u8>> {
+    match conn {
+        Some((reader, _)) => read_frame(reader).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends queued commands to swhks over one persistent connection and logs what became of them.
+/// Returns once every sender is gone and the queue is empty.
+pub async fn sender(mut rx: Receiver<Command>, socket_path: PathBuf, secret_path: PathBuf) {
+    let mut conn: Option<Connection> = None;
+    let mut next_id = 0u64;
+    let mut pending: HashMap<u64, Command> = HashMap::new();
+    // Set after a rejected connection was retried, cleared by the next reply that isn't one.
+    let mut retried = false;
+    loop {
+        tokio::select! {
+            command = rx.recv() => {
+                let Some(command) = command else { break };
+                next_id += 1;
+                if send_frame(&mut conn, &socket_path, &secret_path, &encode_run(next_id, &command)).await {
+                    log::debug!("Sent command {next_id} to swhks: {:?}", command.command);
+                    pending.insert(next_id, command);
+                }
+            }
+
+            frame = next_reply(&mut conn) => {
+                let payload = match frame {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        if e.kind() != io::ErrorKind::UnexpectedEof {
+                            log::warn!("Failed to read from swhks: {e}");
+                        }
+                        if !pending.is_empty() {
+                            log::debug!("swhks disconnected with {} command(s) unanswered", pending.len());
+                            pending.clear();
+                        }
+                        conn = None;
+                        continue;
+                    }
+                };
+                let reply = decode_reply(&payload);
+                if !matches!(reply, Ok(Reply::Rejected { .. })) {
+                    retried = false;
+                }
+                match reply {
+                    Ok(Reply::Spawned { id, error: Some(error) }) => {
+                        if let Some(command) = pending.remove(&id) {
+                            log::error!("swhks failed to run {:?}: {error}", command.command);
+                        }
+                    }
+                    Ok(Reply::Spawned { id, error: None }) => {
+                        log::debug!("swhks started command {id}");
+                    }
+                    Ok(Reply::Exited { id, status }) => {
+                        if let Some(command) = pending.remove(&id)
+                            && status != ExitStatus::Code(0)
+                        {
+                            log::warn!("Command {:?} ({}) ended with {status}", command.command, command.trigger);
+                        }
+                    }
+                    Ok(Reply::Skipped { id, reason }) => {
+                        if let Some(command) = pending.remove(&id) {
+                            log::info!("swhks did not run {:?}: {reason}", command.command);
+                        }
+                    }
+                    Ok(Reply::Queued { id, reason }) => {
+                        if let Some(command) = pending.get(&id) {
+                            log::info!("swhks queued {:?}: {reason}", command.command);
+                        }
+                    }
+                    // A freshly started swhks (e.g. socket activated by this very connection)
+                    // writes its secret after we read the old one, so read it again and resend.
+                    Ok(Reply::Rejected { reason }) => {
+                        conn = None;
+                        if retried {
+                            log::error!("swhks rejected the connection: {reason}");
+                            pending.clear();
+                            continue;
+                        }
+                        log::info!("swhks rejected the connection ({reason}), reconnecting");
+                        retried = true;
+                        let mut ids = pending.keys().copied().collect::<Vec<_>>();
+                        ids.sort_unstable();
+                        for id in ids {
+                            if !send_frame(&mut conn, &socket_path, &secret_path, &encode_run(id, &pending[&id])).await {
+                                pending.remove(&id);
+                            }
+                        }
+                    }
+                    Err(e) => log::warn!("Ignoring reply from swhks: {e}"),
+                }
+            }
+        }
+    }
+    log::debug!("IPC sender stopped (all senders dropped).");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_frame_layout() {
+        let command = Command {
+            command: "ls".to_string(),
+            trigger: "KEY_A".to_string(),
+            env: vec![("K".to_string(), "v".to_string())],
+            instance: Instance::Toggle,
+            timeout: Some(1000),
+            shell: vec!["sh".to_string()],
+            cwd: None,
+        };
+        #[rustfmt::skip]
+        let expected = [
+            0, 0, 0, 58, PROTOCOL_VERSION, KIND_RUN,
+            0, 0, 0, 0, 0, 0, 0, 7,
+            0, 0, 0, 2, b'l', b's',
+            0, 0, 0, 5, b'K', b'E', b'Y', b'_', b'A',
+            0, 0, 0, 1, 0, 0, 0, 1, b'K', 0, 0, 0, 1, b'v',
+            2, 0, 0, 3, 232,
+            0, 0, 0, 1, 0, 0, 0, 2, b's', b'h',
+            0, 0, 0, 0,
+        ];
+        assert_eq!(encode_run(7, &command), expected);
+    }
+
+    #[test]
+    fn replies_are_decoded() {
+        let spawned = [PROTOCOL_VERSION, KIND_SPAWNED, 0, 0, 0, 0, 0, 0, 0, 7, 0];
+        assert_eq!(decode_reply(&spawned), Ok(Reply::Spawned { id: 7, error: None }));
+        let exited = [PROTOCOL_VERSION, KIND_EXITED, 0, 0, 0, 0, 0, 0, 0, 7, 1, 0, 0, 0, 9];
+        assert_eq!(
+            decode_reply(&exited),
+            Ok(Reply::Exited { id: 7, status: ExitStatus::Signal(9) })
+        );
+        let rejected = [PROTOCOL_VERSION, KIND_REJECTED, 0, 0, 0, 2, b'n', b'o'];
+        assert_eq!(decode_reply(&rejected), Ok(Reply::Rejected { reason: "no".to_string() }));
+        assert!(decode_reply(&[PROTOCOL_VERSION + 1, KIND_SPAWNED]).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use evdev::KeyCode;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TypedKey {
+    pub key: KeyCode,
+    pub shift: bool,
+}
+
+pub type Layout = HashMap<char, TypedKey>;
+
+const LETTERS: [(char, KeyCode); 26] = [
+    ('a', KeyCode::KEY_A),
+    ('b', KeyCode::KEY_B),
+    ('c', KeyCode::KEY_C),
+    ('d', KeyCode::KEY_D),
+    ('e', KeyCode::KEY_E),
+    ('f', KeyCode::KEY_F),
+    ('g', KeyCode::KEY_G),
+    ('h', KeyCode::KEY_H),
+    ('i', KeyCode::KEY_I),
+    ('j', KeyCode::KEY_J),
+    ('k', KeyCode::KEY_K),
+    ('l', KeyCode::KEY_L),
+    ('m', KeyCode::KEY_M),
+    ('n', KeyCode::KEY_N),
+    ('o', KeyCode::KEY_O),
+    ('p', KeyCode::KEY_P),
+    ('q', KeyCode::KEY_Q),
+    ('r', KeyCode::KEY_R),
+    ('s', KeyCode::KEY_S),
+    ('t', KeyCode::KEY_T),
+    ('u', KeyCode::KEY_U),
+    ('v', KeyCode::KEY_V),
+    ('w', KeyCode::KEY_W),
+    ('x', KeyCode::KEY_X),
+    ('y', KeyCode::KEY_Y),
+    ('z', KeyCode::KEY_Z),
+];
+
+// (unshifted, shifted, key)
+const US_SYMBOLS: [(char, char, KeyCode); 21] = [
+    ('1', '!', KeyCode::KEY_1),
+    ('2', '@', KeyCode::KEY_2),
+    ('3', '#', KeyCode::KEY_3),
+    ('4', '$', KeyCode::KEY_4),
+    ('5', '%', KeyCode::KEY_5),
+    ('6', '^', KeyCode::KEY_6),
+    ('7', '&', KeyCode::KEY_7),
+    ('8', '*', KeyCode::KEY_8),
+    ('9', '(', KeyCode::KEY_9),
+    ('0', ')', KeyCode::KEY_0),
+    ('-', '_', KeyCode::KEY_MINUS),
+    ('=', '+', KeyCode::KEY_EQUAL),
+    ('[', '{', KeyCode::KEY_LEFTBRACE),
+    (']', '}', KeyCode::KEY_RIGHTBRACE),
+    ('\\', '|', KeyCode::KEY_BACKSLASH),
+    (';', ':', KeyCode::KEY_SEMICOLON),
+    ('\'', '"', KeyCode::KEY_APOSTROPHE),
+    (',', '<', KeyCode::KEY_COMMA),
+    ('.', '>', KeyCode::KEY_DOT),
+    ('/', '?', KeyCode::KEY_SLASH),
+    ('`', '~', KeyCode::KEY_GRAVE),
+];
+
+/// US QWERTY, `general.layout` entries are applied on top of it.
+pub fn us_qwerty() -> Layout {
+    let mut layout = Layout::new();
+    for (c, key) in LETTERS {
+        layout.insert(c, TypedKey { key, shift: false });
+        layout.insert(c.to_ascii_uppercase(), TypedKey { key, shift: true });
+    }
+    for (plain, shifted, key) in US_SYMBOLS {
+        layout.insert(plain, TypedKey { key, shift: false });
+        layout.insert(shifted, TypedKey { key, shift: true });
+    }
+    layout.insert(' ', TypedKey { key: KeyCode::KEY_SPACE, shift: false });
+    layout.insert('\n', TypedKey { key: KeyCode::KEY_ENTER, shift: false });
+    layout.insert('\t', TypedKey { key: KeyCode::KEY_TAB, shift: false });
+    layout
+}
@@ -1,18 +1,61 @@
 use crate::config::{KeyAction, MacroDef, MacroStep, MacroType, MovePath, MoveType};
+use crate::layout::TypedKey;
+use crate::rel_mask::WHEEL_HI_RES_DETENT;
 use evdev::{InputEvent, KeyCode, RelativeAxisCode};
 use std::f64::consts::PI;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use tokio::sync::mpsc::Sender;
-use tokio::time::{Duration, sleep};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::{Duration, Instant, sleep};
 
-const STEP_INTERVAL_MS: u64 = 8;
+/// Everything a running macro asks of the main loop, in the order the steps produced it.
+pub enum MacroOutput {
+    Events(Vec<InputEvent>),
+    Exec(String),
+    EnterMode(String),
+    /// Sent once the macro with this id has returned, after all of its other output.
+    Finished(u64),
+}
+
+/// Share of the distance covered at time `t`, both running from 0 to 1.
+pub fn ease(move_type: MoveType, t: f64) -> f64 {
+    match move_type {
+        MoveType::Constant => t,
+        MoveType::Accelerate => t * t,
+        MoveType::Decelerate => 2.0 * t - t * t,
+        MoveType::EaseInOut => {
+            if t < 0.5 {
+                2.0 * t * t
+            } else {
+                1.0 - (2.0 - 2.0 * t).powi(2) / 2.0
+            }
+        }
+        MoveType::Bezier { x1, y1, x2, y2 } => {
+            let at = |p1: f64, p2: f64, s: f64| {
+                3.0 * (1.0 - s).powi(2) * s * p1 + 3.0 * (1.0 - s) * s * s * p2 + s.powi(3)
+            };
+            // x(s) never decreases when x1 and x2 are within 0..=1, so bisection finds s for t.
+            let (mut lo, mut hi) = (0.0, 1.0);
+            for _ in 0..50 {
+                let mid = (lo + hi) / 2.0;
+                if at(x1, x2, mid) < t {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
+            }
+            at(y1, y2, (lo + hi) / 2.0)
+        }
+    }
+}
 
 pub fn interpolate_direct(
     total_x: i32,
     total_y: i32,
     n: usize,
-    speed: MoveType,
+    move_type: MoveType,
 ) -> Vec<(i32, i32)> {
     if n == 0 {
         return vec![];
@@ -22,12 +65,7 @@ pub fn interpolate_direct(
     let mut acc_y = 0i64;
     let nf = n as f64;
     for i in 1..=n {
-        let t = i as f64 / nf;
-        let frac = match speed {
-            MoveType::Constant => t,
-            MoveType::Accelerate => t * t,
-            MoveType::Decelerate => 2.0 * t - t * t,
-        };
+        let frac = if i == n { 1.0 } else { ease(move_type, i as f64 / nf) };
         let pos_x = (total_x as f64 * frac).round() as i64;
         let pos_y = (total_y as f64 * frac).round() as i64;
         result.push(((pos_x - acc_x) as i32, (pos_y - acc_y) as i32));
@@ -42,7 +80,7 @@ pub fn interpolate_arc(
     total_y: i32,
     clockwise: bool,
     n: usize,
-    speed: MoveType,
+    move_type: MoveType,
 ) -> Vec<(i32, i32)> {
     if n == 0 {
         return vec![];
@@ -79,12 +117,7 @@ pub fn interpolate_arc(
     let mut acc_y = 0i64;
 
     for i in 1..=n {
-        let t = i as f64 / nf;
-        let frac = match speed {
-            MoveType::Constant => t,
-            MoveType::Accelerate => t * t,
-            MoveType::Decelerate => 2.0 * t - t * t,
-        };
+        let frac = if i == n { 1.0 } else { ease(move_type, i as f64 / nf) };
         let angle = start_angle + sweep * frac;
         let pos_x = (mx + r * angle.cos()).round() as i64;
         let pos_y = (my + r * angle.sin()).round() as i64;
@@ -115,19 +148,64 @@ fn rel_event(axis: RelativeAxisCode, value: i32) -> InputEvent {
     InputEvent::new(evdev::EventType::RELATIVE.0, axis.0, value)
 }
 
-async fn emit(tx: &Sender<Vec<InputEvent>>, events: Vec<InputEvent>) {
-    let _ = tx.send(events).await;
+async fn emit(tx: &Sender<MacroOutput>, events: Vec<InputEvent>) {
+    let _ = tx.send(MacroOutput::Events(events)).await;
+}
+
+// Everything a running macro shares across its steps.
+struct MacroContext<'a> {
+    tx: &'a Sender<MacroOutput>,
+    stop: &'a AtomicBool,
+    key_delay: u32,
+    speed: f64,
+    step_interval: u64,
+    rng: AtomicU64,
+    keys: Mutex<Receiver<(KeyCode, i32)>>,
+}
+
+impl MacroContext<'_> {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    // xorshift64, jitter only has to look irregular, not be unpredictable.
+    fn next_random(&self) -> u64 {
+        let mut x = self.rng.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.store(x, Ordering::Relaxed);
+        x
+    }
+
+    // Applies the macro's `speed=` to a duration from the config.
+    fn scaled(&self, ms: u64) -> u64 {
+        (ms as f64 / self.speed).round() as u64
+    }
+
+    /// Sleeps for the scaled `ms` in `step_interval` slices so a stop request cancels long
+    /// waits promptly.
+    async fn wait(&self, ms: u64) {
+        let deadline = Instant::now() + Duration::from_millis(self.scaled(ms));
+        while !self.stopped() {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            sleep((deadline - now).min(Duration::from_millis(self.step_interval))).await;
+        }
+    }
 }
 
 async fn execute_key_action(
     key: KeyCode,
     action: KeyAction,
-    tx: &Sender<Vec<InputEvent>>,
+    ctx: &MacroContext<'_>,
     pressed: &mut Vec<KeyCode>,
 ) {
     match action {
         KeyAction::Down => {
-            emit(tx, vec![key_press_event(key)]).await;
+            emit(ctx.tx, vec![key_press_event(key)]).await;
             if !pressed.contains(&key) {
                 pressed.push(key);
             }
@@ -137,7 +215,7 @@ async fn execute_key_action(
                 log::warn!("macro 'up' on key {:?} that is not down — skipping", key);
                 return;
             }
-            emit(tx, vec![key_release_event(key)]).await;
+            emit(ctx.tx, vec![key_release_event(key)]).await;
             pressed.retain(|&k| k != key);
         }
         KeyAction::Click => {
@@ -145,13 +223,14 @@ async fn execute_key_action(
                 log::warn!("macro 'click' on key {:?} that is already down — skipping", key);
                 return;
             }
-            emit(tx, vec![key_press_event(key)]).await;
-            emit(tx, vec![key_release_event(key)]).await;
+            emit(ctx.tx, vec![key_press_event(key)]).await;
+            ctx.wait(ctx.key_delay as u64).await;
+            emit(ctx.tx, vec![key_release_event(key)]).await;
         }
     }
 }
 
-async fn release_all_pressed(pressed: &[KeyCode], tx: &Sender<Vec<InputEvent>>) {
+async fn release_all_pressed(pressed: &[KeyCode], tx: &Sender<MacroOutput>) {
     if pressed.is_empty() {
         return;
     }
@@ -165,16 +244,15 @@ async fn execute_move(
     duration: u32,
     move_type: MoveType,
     path: &MovePath,
-    tx: &Sender<Vec<InputEvent>>,
-    stop: &AtomicBool,
+    ctx: &MacroContext<'_>,
 ) {
-    let n_steps = ((duration as u64) / STEP_INTERVAL_MS).max(1) as usize;
+    let n_steps = (ctx.scaled(duration as u64) / ctx.step_interval).max(1) as usize;
     let deltas = match path {
         MovePath::Direct => interpolate_direct(x, y, n_steps, move_type),
         MovePath::Arc { clockwise } => interpolate_arc(x, y, *clockwise, n_steps, move_type),
     };
     for (dx, dy) in deltas {
-        if stop.load(Ordering::Relaxed) {
+        if ctx.stopped() {
             return;
         }
         let mut events = vec![];
@@ -185,36 +263,149 @@ async fn execute_move(
             events.push(rel_event(RelativeAxisCode::REL_Y, dy));
         }
         if !events.is_empty() {
-            emit(tx, events).await;
+            emit(ctx.tx, events).await;
+        }
+        sleep(Duration::from_millis(ctx.step_interval)).await;
+    }
+}
+
+// Emits hi-res wheel events every step and a low-res event whenever a full detent is crossed,
+// the same way the kernel reports a physical hi-res wheel.
+async fn execute_scroll(
+    x: i32,
+    y: i32,
+    duration: u32,
+    move_type: MoveType,
+    ctx: &MacroContext<'_>,
+) {
+    let n_steps = (ctx.scaled(duration as u64) / ctx.step_interval).max(1) as usize;
+    let deltas = interpolate_direct(
+        x.saturating_mul(WHEEL_HI_RES_DETENT),
+        y.saturating_mul(WHEEL_HI_RES_DETENT),
+        n_steps,
+        move_type,
+    );
+    let mut acc_x = 0i32;
+    let mut acc_y = 0i32;
+    for (dx, dy) in deltas {
+        if ctx.stopped() {
+            return;
+        }
+        let mut events = vec![];
+        for (delta, acc, hi_res, low_res) in [
+            (dy, &mut acc_y, RelativeAxisCode::REL_WHEEL_HI_RES, RelativeAxisCode::REL_WHEEL),
+            (dx, &mut acc_x, RelativeAxisCode::REL_HWHEEL_HI_RES, RelativeAxisCode::REL_HWHEEL),
+        ] {
+            if delta == 0 {
+                continue;
+            }
+            let before = *acc / WHEEL_HI_RES_DETENT;
+            *acc += delta;
+            events.push(rel_event(hi_res, delta));
+            let detents = *acc / WHEEL_HI_RES_DETENT - before;
+            if detents != 0 {
+                events.push(rel_event(low_res, detents));
+            }
+        }
+        if !events.is_empty() {
+            emit(ctx.tx, events).await;
+        }
+        sleep(Duration::from_millis(ctx.step_interval)).await;
+    }
+}
+
+async fn execute_type(keys: &[TypedKey], delay: u32, ctx: &MacroContext<'_>) {
+    for typed in keys {
+        if ctx.stopped() {
+            return;
+        }
+        if typed.shift {
+            emit(ctx.tx, vec![key_press_event(KeyCode::KEY_LEFTSHIFT)]).await;
+        }
+        emit(ctx.tx, vec![key_press_event(typed.key)]).await;
+        ctx.wait(ctx.key_delay as u64).await;
+        emit(ctx.tx, vec![key_release_event(typed.key)]).await;
+        if typed.shift {
+            emit(ctx.tx, vec![key_release_event(KeyCode::KEY_LEFTSHIFT)]).await;
+        }
+        ctx.wait(delay as u64).await;
+    }
+}
+
+async fn execute_wait(duration: u32, jitter: u32, ctx: &MacroContext<'_>) {
+    let offset = if jitter == 0 {
+        0
+    } else {
+        (ctx.next_random() % (2 * jitter as u64 + 1)) as i64 - jitter as i64
+    };
+    ctx.wait((duration as i64 + offset).max(0) as u64).await;
+}
+
+// Key events that arrived before the step started don't count.
+async fn execute_wait_key(key: KeyCode, down: bool, timeout: Option<u32>, ctx: &MacroContext<'_>) {
+    let mut keys = ctx.keys.lock().await;
+    while keys.try_recv().is_ok() {}
+    let deadline = timeout.map(|ms| Instant::now() + Duration::from_millis(ms as u64));
+    let value = if down { 1 } else { 0 };
+    while !ctx.stopped() {
+        let mut slice = Duration::from_millis(ctx.step_interval);
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                log::debug!("macro wait_key {key:?} timed out");
+                return;
+            }
+            slice = slice.min(deadline - now);
+        }
+        match tokio::time::timeout(slice, keys.recv()).await {
+            Ok(Some((k, v))) if k == key && v == value => return,
+            Ok(Some(_)) | Err(_) => {}
+            Ok(None) => return,
         }
-        sleep(Duration::from_millis(STEP_INTERVAL_MS)).await;
     }
 }
 
 fn execute_steps<'a>(
     steps: &'a [MacroStep],
-    tx: &'a Sender<Vec<InputEvent>>,
-    stop: &'a AtomicBool,
+    ctx: &'a MacroContext<'a>,
     pressed: &'a mut Vec<KeyCode>,
 ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'a>> {
     Box::pin(async move {
         for step in steps {
-            if stop.load(Ordering::Relaxed) {
+            if ctx.stopped() {
                 return;
             }
             match step {
                 MacroStep::KeyAction { key, action } => {
-                    execute_key_action(*key, *action, tx, pressed).await;
+                    execute_key_action(*key, *action, ctx, pressed).await;
                 }
                 MacroStep::Move { x, y, duration, move_type, path } => {
-                    execute_move(*x, *y, *duration, *move_type, path, tx, stop).await;
+                    execute_move(*x, *y, *duration, *move_type, path, ctx).await;
+                }
+                MacroStep::Scroll { x, y, duration, move_type } => {
+                    execute_scroll(*x, *y, *duration, *move_type, ctx).await;
+                }
+                MacroStep::Type { keys, delay } => {
+                    execute_type(keys, *delay, ctx).await;
+                }
+                MacroStep::Wait { duration, jitter } => {
+                    execute_wait(*duration, *jitter, ctx).await;
+                }
+                MacroStep::WaitKey { key, down, timeout } => {
+                    execute_wait_key(*key, *down, *timeout, ctx).await;
+                }
+                MacroStep::Exec(command) => {
+                    let _ = ctx.tx.send(MacroOutput::Exec(command.clone())).await;
+                }
+                MacroStep::Enter(mode) => {
+                    let _ = ctx.tx.send(MacroOutput::EnterMode(mode.clone())).await;
                 }
                 MacroStep::Repeat { count, steps: inner } => {
                     for _ in 0..*count {
-                        if stop.load(Ordering::Relaxed) {
+                        if ctx.stopped() {
                             return;
                         }
-                        execute_steps(inner, tx, stop, pressed).await;
+                        execute_steps(inner, ctx, pressed).await;
                     }
                 }
             }
@@ -222,18 +413,81 @@ fn execute_steps<'a>(
     })
 }
 
-pub async fn run_macro(macro_def: MacroDef, tx: Sender<Vec<InputEvent>>, stop: Arc<AtomicBool>) {
+pub async fn run_macro(
+    macro_def: MacroDef,
+    tx: Sender<MacroOutput>,
+    keys: Receiver<(KeyCode, i32)>,
+    stop: Arc<AtomicBool>,
+) {
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    let ctx = MacroContext {
+        tx: &tx,
+        stop: &stop,
+        key_delay: macro_def.key_delay,
+        speed: macro_def.speed,
+        step_interval: macro_def.step_interval as u64,
+        rng: AtomicU64::new(seed | 1),
+        keys: Mutex::new(keys),
+    };
     let mut pressed: Vec<KeyCode> = vec![];
     match macro_def.macro_type {
         MacroType::Simple => {
-            execute_steps(&macro_def.steps, &tx, &stop, &mut pressed).await;
+            execute_steps(&macro_def.steps, &ctx, &mut pressed).await;
         }
-        MacroType::Endless | MacroType::Hold => loop {
-            if stop.load(Ordering::Relaxed) {
+        MacroType::Endless | MacroType::Hold | MacroType::Toggle => loop {
+            if ctx.stopped() {
                 break;
             }
-            execute_steps(&macro_def.steps, &tx, &stop, &mut pressed).await;
+            execute_steps(&macro_def.steps, &ctx, &mut pressed).await;
         },
     }
     release_all_pressed(&pressed, &tx).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [MoveType; 6] = [
+        MoveType::Constant,
+        MoveType::Accelerate,
+        MoveType::Decelerate,
+        MoveType::EaseInOut,
+        MoveType::Bezier { x1: 0.42, y1: 0.0, x2: 0.58, y2: 1.0 },
+        MoveType::Bezier { x1: 0.3, y1: -0.5, x2: 0.7, y2: 1.5 },
+    ];
+    const TOTALS: [(i32, i32); 5] = [(0, 0), (1, -1), (100, 37), (-250, 999), (7, 0)];
+    const STEPS: [usize; 5] = [1, 2, 3, 8, 125];
+
+    fn sum(deltas: &[(i32, i32)]) -> (i32, i32) {
+        deltas.iter().fold((0, 0), |(ax, ay), (dx, dy)| (ax + dx, ay + dy))
+    }
+
+    #[test]
+    fn direct_deltas_sum_to_total() {
+        for curve in CURVES {
+            for (x, y) in TOTALS {
+                for n in STEPS {
+                    let deltas = interpolate_direct(x, y, n, curve);
+                    assert_eq!(deltas.len(), n);
+                    assert_eq!(sum(&deltas), (x, y), "{curve:?} over {n} steps");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn arc_deltas_sum_to_total() {
+        for curve in CURVES {
+            for (x, y) in TOTALS {
+                for n in STEPS {
+                    for clockwise in [true, false] {
+                        let deltas = interpolate_arc(x, y, clockwise, n, curve);
+                        assert_eq!(deltas.len(), n);
+                        assert_eq!(sum(&deltas), (x, y), "{curve:?} over {n} steps");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use crate::config::{KeyAction, MacroStep, MovePath, MoveType};
+use crate::rel_mask;
+use evdev::{KeyCode, RelativeAxisCode};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::time::Instant;
+
+// Pointer motion closer together than this is folded into one `move` step.
+const MOTION_MERGE_MS: u32 = 50;
+
+/// Captures live key and relative-axis events as macro steps for `@record <slot>`.
+pub struct Recorder {
+    pub slot: String,
+    steps: Vec<MacroStep>,
+    last: Instant,
+    motion_open: bool,
+    // Partial hi-res scroll per axis, see `rel_mask::wheel_detents`.
+    hires_wheel: [i32; 2],
+}
+
+impl Recorder {
+    pub fn new(slot: String) -> Self {
+        Recorder {
+            slot,
+            steps: vec![],
+            last: Instant::now(),
+            motion_open: false,
+            hires_wheel: [0; 2],
+        }
+    }
+
+    // Milliseconds since the previous recorded event.
+    fn gap(&mut self) -> u32 {
+        let now = Instant::now();
+        let gap = u32::try_from(now.duration_since(self.last).as_millis()).unwrap_or(u32::MAX);
+        self.last = now;
+        gap
+    }
+
+    fn push(&mut self, gap: u32, step: MacroStep) {
+        if gap > 0 {
+            self.steps.push(MacroStep::Wait { duration: gap, jitter: 0 });
+        }
+        self.steps.push(step);
+    }
+
+    pub fn key(&mut self, key: KeyCode, value: i32) {
+        let action = match value {
+            1 => KeyAction::Down,
+            0 => KeyAction::Up,
+            _ => return,
+        };
+        let gap = self.gap();
+        self.motion_open = false;
+        self.push(gap, MacroStep::KeyAction { key, action });
+    }
+
+    pub fn relative(&mut self, code: RelativeAxisCode, value: i32) {
+        let (dx, dy) = match code {
+            RelativeAxisCode::REL_X => (value, 0),
+            RelativeAxisCode::REL_Y => (0, value),
+            RelativeAxisCode::REL_WHEEL | RelativeAxisCode::REL_HWHEEL => {
+                let (x, y) =
+                    if code == RelativeAxisCode::REL_WHEEL { (0, value) } else { (value, 0) };
+                self.scroll(x, y);
+                return;
+            }
+            RelativeAxisCode::REL_WHEEL_HI_RES | RelativeAxisCode::REL_HWHEEL_HI_RES => {
+                let detents = rel_mask::wheel_detents(&mut self.hires_wheel, code, value) as i32
+                    * value.signum();
+                if detents != 0 {
+                    let (x, y) = if code == RelativeAxisCode::REL_WHEEL_HI_RES {
+                        (0, detents)
+                    } else {
+                        (detents, 0)
+                    };
+                    self.scroll(x, y);
+                }
+                return;
+            }
+            _ => return,
+        };
+        let gap = self.gap();
+        if self.motion_open
+            && gap < MOTION_MERGE_MS
+            && let Some(MacroStep::Move { x, y, duration, .. }) = self.steps.last_mut()
+        {
+            *x += dx;
+            *y += dy;
+            *duration += gap;
+            return;
+        }
+        self.motion_open = true;
+        self.push(
+            gap,
+            MacroStep::Move {
+                x: dx,
+                y: dy,
+                duration: 0,
+                move_type: MoveType::Constant,
+                path: MovePath::Direct,
+            },
+        );
+    }
+
+    // Scroll amounts are in detents, like the `scroll` step.
+    fn scroll(&mut self, x: i32, y: i32) {
+        let gap = self.gap();
+        self.motion_open = false;
+        self.push(gap, MacroStep::Scroll { x, y, duration: 0, move_type: MoveType::Constant });
+    }
+
+    /// Drops presses still held when recording stopped (usually the `@record` hotkey itself)
+    /// and releases of keys pressed before it started, then tidies up the waits around them.
+    pub fn finish(self) -> Vec<MacroStep> {
+        let mut held = HashSet::new();
+        let mut keep = vec![true; self.steps.len()];
+        for (i, step) in self.steps.iter().enumerate() {
+            if let MacroStep::KeyAction { key, action } = step {
+                match action {
+                    KeyAction::Down => {
+                        held.insert(*key);
+                    }
+                    _ => keep[i] = held.remove(key),
+                }
+            }
+        }
+        for (i, step) in self.steps.iter().enumerate().rev() {
+            if let MacroStep::KeyAction { key, action: KeyAction::Down } = step
+                && held.remove(key)
+            {
+                keep[i] = false;
+            }
+        }
+
+        let mut steps: Vec<MacroStep> = vec![];
+        for (step, keep) in self.steps.into_iter().zip(keep) {
+            if !keep {
+                continue;
+            }
+            match (steps.last_mut(), step) {
+                (
+                    Some(MacroStep::Wait { duration: total, .. }),
+                    MacroStep::Wait { duration, .. },
+                ) => *total = total.saturating_add(duration),
+                (_, step) => steps.push(step),
+            }
+        }
+        while matches!(steps.last(), Some(MacroStep::Wait { .. })) {
+            steps.pop();
+        }
+        if matches!(steps.first(), Some(MacroStep::Wait { .. })) {
+            steps.remove(0);
+        }
+        steps
+    }
+}
+
+/// Renders recorded steps as a top-level `macro` node that can be pasted into the config.
+pub fn to_kdl(slot: &str, steps: &[MacroStep]) -> String {
+    let mut out = format!("macro {slot:?} {{\n");
+    for step in steps {
+        let _ = match step {
+            MacroStep::KeyAction { key, action } => {
+                let action = match action {
+                    KeyAction::Down => "down",
+                    KeyAction::Up => "up",
+                    KeyAction::Click => "click",
+                };
+                writeln!(out, "  {key:?} {action:?}")
+            }
+            MacroStep::Move { x, y, duration, .. } => {
+                writeln!(out, "  move x={x} y={y} duration={duration}")
+            }
+            MacroStep::Scroll { x, y, .. } => writeln!(out, "  scroll x={x} y={y}"),
+            MacroStep::Wait { duration, .. } => writeln!(out, "  wait {duration}"),
+            // The recorder only produces the steps above.
+            other => writeln!(out, "  // unsupported step: {other:?}"),
+        };
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmatched_keys_are_dropped_and_waits_merged() {
+        let mut recorder = Recorder::new("demo".to_string());
+        let key = |key, action| MacroStep::KeyAction { key, action };
+        let wait = |duration| MacroStep::Wait { duration, jitter: 0 };
+        recorder.steps = vec![
+            key(KeyCode::KEY_LEFTMETA, KeyAction::Up),
+            wait(5),
+            key(KeyCode::KEY_A, KeyAction::Down),
+            wait(10),
+            key(KeyCode::KEY_F9, KeyAction::Up),
+            wait(20),
+            key(KeyCode::KEY_A, KeyAction::Up),
+            wait(30),
+            key(KeyCode::KEY_F9, KeyAction::Down),
+        ];
+        let steps = recorder.finish();
+        assert_eq!(
+            steps,
+            [key(KeyCode::KEY_A, KeyAction::Down), wait(30), key(KeyCode::KEY_A, KeyAction::Up)]
+        );
+        assert_eq!(
+            to_kdl("demo", &steps),
+            "macro \"demo\" {\n  KEY_A \"down\"\n  wait 30\n  KEY_A \"up\"\n}"
+        );
+    }
+
+    #[test]
+    fn hi_res_wheel_is_recorded_in_detents() {
+        let mut recorder = Recorder::new("demo".to_string());
+        for value in [60, 60, 120, -30, -90] {
+            recorder.relative(RelativeAxisCode::REL_WHEEL_HI_RES, value);
+        }
+        recorder.relative(RelativeAxisCode::REL_HWHEEL_HI_RES, 240);
+        let scrolls: Vec<_> = recorder
+            .finish()
+            .into_iter()
+            .filter_map(|step| match step {
+                MacroStep::Scroll { x, y, .. } => Some((x, y)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(scrolls, [(0, 1), (0, 1), (0, -1), (2, 0)]);
+    }
+}
@@ -13,6 +13,27 @@ struct InputMask {
 
 ioctl_write_ptr!(eviocsmask, b'E', 0x93, InputMask);
 
+/// Hi-res wheel units per physical detent, see Documentation/input/event-codes.rst
+pub const WHEEL_HI_RES_DETENT: i32 = 120;
+
+/// Detents in one wheel event. Low-res events count their value, fast wheels report several
+/// detents at once. Hi-res events are accumulated until a full detent is reached, the
+/// remainder is dropped when the scroll direction flips.
+pub fn wheel_detents(hires_wheel: &mut [i32; 2], code: RelativeAxisCode, value: i32) -> u32 {
+    let acc = match code {
+        RelativeAxisCode::REL_WHEEL_HI_RES => &mut hires_wheel[0],
+        RelativeAxisCode::REL_HWHEEL_HI_RES => &mut hires_wheel[1],
+        _ => return value.unsigned_abs(),
+    };
+    if acc.signum() == -value.signum() {
+        *acc = 0;
+    }
+    *acc += value;
+    let detents = *acc / WHEEL_HI_RES_DETENT;
+    *acc -= detents * WHEEL_HI_RES_DETENT;
+    detents.unsigned_abs()
+}
+
 pub fn allowed_rel_axes(supported: Option<&AttributeSetRef<RelativeAxisCode>>) -> u16 {
     let has = |code| supported.is_some_and(|axes| axes.contains(code));
     let mut mask = u16::MAX;
@@ -42,3 +63,18 @@ pub fn apply_kernel_rel_mask(fd: RawFd, mask: u16) -> nix::Result<()> {
     };
     unsafe { eviocsmask(fd, &input_mask) }.map(drop)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wheel_events_count_every_detent() {
+        let mut hires_wheel = [0; 2];
+        assert_eq!(wheel_detents(&mut hires_wheel, RelativeAxisCode::REL_WHEEL, -3), 3);
+        assert_eq!(wheel_detents(&mut hires_wheel, RelativeAxisCode::REL_WHEEL_HI_RES, 60), 0);
+        assert_eq!(wheel_detents(&mut hires_wheel, RelativeAxisCode::REL_WHEEL_HI_RES, 300), 3);
+        assert_eq!(wheel_detents(&mut hires_wheel, RelativeAxisCode::REL_WHEEL_HI_RES, -60), 0);
+        assert_eq!(hires_wheel, [-60, 0]);
+    }
+}
@@ -0,0 +1,73 @@
+//! Checks that a connection really comes from swhkdp before any command from it runs.
+
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// The swhkdp installed next to this swhks, both are built and installed together.
+pub fn default_swhkdp_path() -> Option<PathBuf> {
+    Some(std::env::current_exe().ok()?.parent()?.join("swhkdp"))
+}
+
+// A root process's `/proc/<pid>/exe` isn't readable by the user, argv[0] is the fallback.
+// Only root could fake it, and root is required anyway.
+fn peer_exe(pid: i32) -> Result<PathBuf, String> {
+    match fs::read_link(format!("/proc/{pid}/exe")) {
+        Ok(exe) => Ok(exe),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let cmdline = fs::read(format!("/proc/{pid}/cmdline"))
+                .map_err(|e| format!("can't read cmdline of pid {pid}: {e}"))?;
+            let argv0 = cmdline.split(|&b| b == 0).next().unwrap_or_default();
+            Ok(PathBuf::from(String::from_utf8_lossy(argv0).into_owned()))
+        }
+        Err(e) => Err(format!("can't resolve executable of pid {pid}: {e}")),
+    }
+}
+
+/// Accepts only root peers running the expected swhkdp binary.
+pub fn check_peer(socket: &UnixStream, swhkdp_path: &Path) -> Result<(), String> {
+    let creds = getsockopt(socket, PeerCredentials).map_err(|e| format!("SO_PEERCRED: {e}"))?;
+    if creds.uid() != 0 {
+        return Err(format!("peer pid {} runs as uid {}, not root", creds.pid(), creds.uid()));
+    }
+    let exe = peer_exe(creds.pid())?;
+    let same = match (fs::canonicalize(&exe), fs::canonicalize(swhkdp_path)) {
+        (Ok(exe), Ok(expected)) => exe == expected,
+        _ => false,
+    };
+    if !same {
+        return Err(format!(
+            "peer pid {} runs {}, expected {}",
+            creds.pid(),
+            exe.display(),
+            swhkdp_path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Writes a fresh random secret readable only by the user for swhkdp to present.
+pub fn create_secret(path: &Path) -> std::io::Result<String> {
+    let mut bytes = [0u8; 32];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let secret: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    // Never write through whatever might already sit at that path.
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(secret.as_bytes())?;
+    Ok(secret)
+}
+
+pub fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
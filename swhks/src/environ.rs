@@ -1,15 +1,32 @@
 //! environ.rs
 //! Defines modules and structs for handling environment variables and paths.
 
-use std::{env::VarError, path::PathBuf};
+use std::{
+    env::VarError,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 use nix::unistd;
 
+// Fetching spawns `systemctl`, commands fired in a burst share one result.
+const SESSION_REFRESH: Duration = Duration::from_secs(2);
+
 // The main struct for handling environment variables.
 // Contains the values of the environment variables in the form of PathBuffers.
 pub struct Env {
     pub data_home: PathBuf,
     pub runtime_dir: PathBuf,
+    session: Mutex<Session>,
+}
+
+#[derive(Default)]
+struct Session {
+    vars: Vec<(String, String)>,
+    fetched: Option<Instant>,
+    unavailable: bool,
 }
 
 impl Env {
@@ -29,7 +46,37 @@ impl Env {
             PathBuf::from(format!("/run/user/{}", unistd::Uid::current()))
         });
 
-        Ok(Self { data_home, runtime_dir })
+        Ok(Self { data_home, runtime_dir, session: Mutex::default() })
+    }
+
+    /// The user session's environment as the systemd user manager currently has it. Compositors
+    /// export `WAYLAND_DISPLAY` and friends there (`systemctl --user import-environment`), so it
+    /// stays correct when swhks outlives or predates the session. Empty without systemd.
+    pub fn session_vars(&self) -> Vec<(String, String)> {
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        if session.unavailable || session.fetched.is_some_and(|t| t.elapsed() < SESSION_REFRESH) {
+            return session.vars.clone();
+        }
+        let output = Command::new("systemctl")
+            .args(["--user", "show-environment"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                session.vars = parse_show_environment(&String::from_utf8_lossy(&output.stdout));
+                session.fetched = Some(Instant::now());
+            }
+            Ok(output) => {
+                log::debug!("systemctl --user show-environment failed: {}", output.status);
+                session.fetched = Some(Instant::now());
+            }
+            Err(e) => {
+                log::info!("Not importing the session environment, can't run systemctl: {e}");
+                session.unavailable = true;
+            }
+        }
+        session.vars.clone()
     }
 
     /// Function to ensure paths are available.
@@ -61,3 +108,55 @@ impl Env {
         std::env::var(name).map(PathBuf::from)
     }
 }
+
+// One `NAME=value` per line, values that need it are quoted as `$'...'` with C escapes.
+fn parse_show_environment(output: &str) -> Vec<(String, String)> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| {
+            let value = match value.strip_prefix("$'").and_then(|v| v.strip_suffix('\'')) {
+                Some(quoted) => unescape(quoted),
+                None => value.to_string(),
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn unescape(quoted: &str) -> String {
+    let mut value = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('t') => value.push('\t'),
+            Some('r') => value.push('\r'),
+            Some(c) => value.push(c),
+            None => value.push('\\'),
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn show_environment_output_is_parsed() {
+        let output = "WAYLAND_DISPLAY=wayland-1\nMOTD=$'hello\\n\\'world\\''\nEMPTY=\n";
+        assert_eq!(
+            parse_show_environment(output),
+            [
+                ("WAYLAND_DISPLAY".to_string(), "wayland-1".to_string()),
+                ("MOTD".to_string(), "hello\n'world'".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+    }
+}
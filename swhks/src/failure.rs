@@ -0,0 +1,33 @@
+//! Reports commands that failed, through the log and the optional `--on-failure` hook.
+
+use crate::Server;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
+
+pub fn report(server: &Server, command: &str, status: ExitStatus, stderr_tail: &[String]) {
+    let stderr = stderr_tail.join("\n");
+    if stderr.is_empty() {
+        log::warn!("{command:?} failed with {status}");
+    } else {
+        log::warn!("{command:?} failed with {status}, stderr ends with:\n{stderr}");
+    }
+    let Some(hook) = &server.on_failure else { return };
+    // The hook runs on this command's waiter thread, so waiting for it blocks nothing else.
+    let result = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .envs(server.environ.session_vars())
+        .env("SWHKS_COMMAND", command)
+        .env("SWHKS_EXIT_CODE", status.code().map(|c| c.to_string()).unwrap_or_default())
+        .env("SWHKS_SIGNAL", status.signal().map(|s| s.to_string()).unwrap_or_default())
+        .env("SWHKS_STDERR", &stderr)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("The on-failure hook failed with {status}"),
+        Err(e) => log::error!("Failed to run the on-failure hook: {e}"),
+    }
+}
@@ -0,0 +1,235 @@
+//! Requests from swhkdp and the replies sent back.
+//!
+//! Both directions use the same framing: a big-endian `u32` payload length, then the payload.
+//! A payload starts with the protocol version and a message kind byte. Integers are big-endian,
+//! strings are a `u32` length followed by UTF-8 bytes. swhkdp keeps its own copy of this format
+//! in `swhkdp/src/ipc.rs`, the two must change together.
+
+use std::io::{self, Read};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// swhkdp -> swhks
+const KIND_RUN: u8 = 1;
+const KIND_HELLO: u8 = 4;
+// swhks -> swhkdp
+const KIND_SPAWNED: u8 = 2;
+const KIND_EXITED: u8 = 3;
+const KIND_SKIPPED: u8 = 5;
+const KIND_QUEUED: u8 = 6;
+const KIND_REJECTED: u8 = 7;
+
+const MAX_FRAME_LEN: u32 = 1 << 20;
+
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// First frame on a connection when `swhks.secret` exists.
+    Hello {
+        secret: String,
+    },
+    Run(Run),
+}
+
+/// A shell command to run on behalf of swhkdp.
+#[derive(Debug, PartialEq)]
+pub struct Run {
+    pub id: u64,
+    pub command: String,
+    /// Hotkey that fired it, empty when a macro `exec` step ran it.
+    pub trigger: String,
+    pub env: Vec<(String, String)>,
+    pub instance: Instance,
+    /// Milliseconds before the command is killed.
+    pub timeout: Option<u32>,
+    /// Program and arguments the command is appended to.
+    pub shell: Vec<String>,
+    pub cwd: Option<String>,
+}
+
+/// What to do when the hotkey's previous command is still running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instance {
+    Multiple,
+    Single,
+    Toggle,
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        if self.0.len() < n {
+            return Err("truncated message".to_string());
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+pub fn decode_request(payload: &[u8]) -> Result<Request, String> {
+    let mut d = Decoder(payload);
+    let version = d.u8()?;
+    if version != PROTOCOL_VERSION {
+        return Err(format!("unsupported protocol version {version}"));
+    }
+    match d.u8()? {
+        KIND_RUN => {
+            let id = d.u64()?;
+            let command = d.string()?;
+            let trigger = d.string()?;
+            let mut env = vec![];
+            for _ in 0..d.u32()? {
+                env.push((d.string()?, d.string()?));
+            }
+            let instance = match d.u8()? {
+                0 => Instance::Multiple,
+                1 => Instance::Single,
+                2 => Instance::Toggle,
+                n => return Err(format!("unknown instance mode {n}")),
+            };
+            let timeout = Some(d.u32()?).filter(|&ms| ms > 0);
+            let mut shell = vec![];
+            for _ in 0..d.u32()? {
+                shell.push(d.string()?);
+            }
+            let cwd = Some(d.string()?).filter(|cwd| !cwd.is_empty());
+            Ok(Request::Run(Run { id, command, trigger, env, instance, timeout, shell, cwd }))
+        }
+        KIND_HELLO => Ok(Request::Hello { secret: d.string()? }),
+        kind => Err(format!("unknown message kind {kind}")),
+    }
+}
+
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {len} bytes")));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn frame(payload: Vec<u8>) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Tells swhkdp whether command `id` was started, `error` explains why it wasn't.
+pub fn encode_spawned(id: u64, error: Option<&str>) -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION, KIND_SPAWNED];
+    payload.extend_from_slice(&id.to_be_bytes());
+    match error {
+        None => payload.push(0),
+        Some(error) => {
+            payload.push(1);
+            payload.extend_from_slice(&(error.len() as u32).to_be_bytes());
+            payload.extend_from_slice(error.as_bytes());
+        }
+    }
+    frame(payload)
+}
+
+/// Tells swhkdp that command `id` was deliberately not started.
+pub fn encode_skipped(id: u64, reason: &str) -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION, KIND_SKIPPED];
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+    payload.extend_from_slice(reason.as_bytes());
+    frame(payload)
+}
+
+/// Tells swhkdp that command `id` has to wait before it can start.
+/// Sent before closing a connection that failed authentication.
+pub fn encode_rejected(reason: &str) -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION, KIND_REJECTED];
+    payload.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+    payload.extend_from_slice(reason.as_bytes());
+    frame(payload)
+}
+
+pub fn encode_queued(id: u64, reason: &str) -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION, KIND_QUEUED];
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+    payload.extend_from_slice(reason.as_bytes());
+    frame(payload)
+}
+
+pub fn encode_exited(id: u64, status: ExitStatus) -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION, KIND_EXITED];
+    payload.extend_from_slice(&id.to_be_bytes());
+    match (status.code(), status.signal()) {
+        (Some(code), _) => {
+            payload.push(0);
+            payload.extend_from_slice(&code.to_be_bytes());
+        }
+        (None, signal) => {
+            payload.push(1);
+            payload.extend_from_slice(&signal.unwrap_or(0).to_be_bytes());
+        }
+    }
+    frame(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_frame_is_decoded() {
+        #[rustfmt::skip]
+        let frame = [
+            0, 0, 0, 58, PROTOCOL_VERSION, KIND_RUN,
+            0, 0, 0, 0, 0, 0, 0, 7,
+            0, 0, 0, 2, b'l', b's',
+            0, 0, 0, 5, b'K', b'E', b'Y', b'_', b'A',
+            0, 0, 0, 1, 0, 0, 0, 1, b'K', 0, 0, 0, 1, b'v',
+            2, 0, 0, 3, 232,
+            0, 0, 0, 1, 0, 0, 0, 2, b's', b'h',
+            0, 0, 0, 0,
+        ];
+        let payload = read_frame(&mut &frame[..]).unwrap();
+        let run = Run {
+            id: 7,
+            command: "ls".to_string(),
+            trigger: "KEY_A".to_string(),
+            env: vec![("K".to_string(), "v".to_string())],
+            instance: Instance::Toggle,
+            timeout: Some(1000),
+            shell: vec!["sh".to_string()],
+            cwd: None,
+        };
+        assert_eq!(decode_request(&payload), Ok(Request::Run(run)));
+    }
+
+    #[test]
+    fn exit_status_is_encoded() {
+        let expected =
+            [0, 0, 0, 15, PROTOCOL_VERSION, KIND_EXITED, 0, 0, 0, 0, 0, 0, 0, 7, 1, 0, 0, 0, 9];
+        assert_eq!(encode_exited(7, ExitStatus::from_raw(9)), expected);
+    }
+}
@@ -0,0 +1,132 @@
+//! Process groups of running commands, keyed by the hotkey that started them.
+
+use nix::sys::signal::{Signal, killpg};
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+// How long a timed out command gets to handle SIGTERM before it's killed.
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+pub struct Jobs {
+    groups: Mutex<HashMap<String, Pid>>,
+    // Groups stopped on purpose, their exit isn't a failure.
+    stopped: Mutex<HashSet<Pid>>,
+    // The queued, not yet started `single`/`toggle` command of each trigger, by command id.
+    queued: Mutex<HashMap<String, u64>>,
+    // Queued commands a `toggle` press took back before they started.
+    cancelled: Mutex<HashSet<u64>>,
+}
+
+impl Jobs {
+    /// Remembers that `trigger` started the process group `pgid`.
+    pub fn insert(&self, trigger: &str, pgid: Pid) {
+        self.groups.lock().unwrap_or_else(|e| e.into_inner()).insert(trigger.to_string(), pgid);
+    }
+
+    // A group stays alive as long as any member does, so commands that fork into the
+    // background (`sh -c "rofi &"`) are still seen as running after the shell exits.
+    fn alive(&self, trigger: &str) -> Option<Pid> {
+        let mut groups = self.groups.lock().unwrap_or_else(|e| e.into_inner());
+        let pgid = *groups.get(trigger)?;
+        if killpg(pgid, None).is_ok() {
+            return Some(pgid);
+        }
+        groups.remove(trigger);
+        None
+    }
+
+    pub fn is_running(&self, trigger: &str) -> bool {
+        self.alive(trigger).is_some()
+    }
+
+    /// Sends SIGTERM to the group `trigger` started, returns whether one was running.
+    pub fn stop(&self, trigger: &str) -> bool {
+        let Some(pgid) = self.alive(trigger) else { return false };
+        if let Err(e) = killpg(pgid, Signal::SIGTERM) {
+            log::warn!("Failed to stop process group {pgid} of {trigger:?}: {e}");
+        }
+        self.stopped.lock().unwrap_or_else(|e| e.into_inner()).insert(pgid);
+        self.groups.lock().unwrap_or_else(|e| e.into_inner()).remove(trigger);
+        true
+    }
+
+    /// Remembers that command `id` of `trigger` is waiting to start.
+    pub fn queue(&self, trigger: &str, id: u64) {
+        self.queued.lock().unwrap_or_else(|e| e.into_inner()).insert(trigger.to_string(), id);
+    }
+
+    pub fn is_queued(&self, trigger: &str) -> bool {
+        self.queued.lock().unwrap_or_else(|e| e.into_inner()).contains_key(trigger)
+    }
+
+    /// Keeps the queued command of `trigger` from starting, returns whether there was one.
+    pub fn cancel_queued(&self, trigger: &str) -> bool {
+        let Some(id) = self.queued.lock().unwrap_or_else(|e| e.into_inner()).remove(trigger) else {
+            return false;
+        };
+        self.cancelled.lock().unwrap_or_else(|e| e.into_inner()).insert(id);
+        true
+    }
+
+    /// Called when command `id` of `trigger` gets its turn, returns whether it may start.
+    pub fn dequeue(&self, trigger: &str, id: u64) -> bool {
+        if self.cancelled.lock().unwrap_or_else(|e| e.into_inner()).remove(&id) {
+            return false;
+        }
+        let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+        if queued.get(trigger) == Some(&id) {
+            queued.remove(trigger);
+        }
+        true
+    }
+
+    /// Whether [`Jobs::stop`] ended the group `pgid`, forgets it either way.
+    pub fn was_stopped(&self, pgid: Pid) -> bool {
+        self.stopped.lock().unwrap_or_else(|e| e.into_inner()).remove(&pgid)
+    }
+}
+
+/// Terminates the process group `pgid` unless `done` fires within `timeout_ms`, escalating to
+/// SIGKILL if SIGTERM doesn't end it. `done` fires (or disconnects) once the command has exited.
+pub fn enforce_timeout(pgid: Pid, timeout_ms: u32, done: Receiver<()>, command: &str) {
+    let timeout = Duration::from_millis(timeout_ms.into());
+    if done.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+        return;
+    }
+    log::warn!("{command:?} timed out after {timeout_ms} ms, sending SIGTERM");
+    if let Err(e) = killpg(pgid, Signal::SIGTERM) {
+        log::warn!("Failed to terminate process group {pgid}: {e}");
+    }
+    if done.recv_timeout(KILL_GRACE) != Err(RecvTimeoutError::Timeout) {
+        log::info!("{command:?} exited after SIGTERM");
+        return;
+    }
+    log::warn!("{command:?} ignored SIGTERM, sending SIGKILL");
+    if let Err(e) = killpg(pgid, Signal::SIGKILL) {
+        log::warn!("Failed to kill process group {pgid}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toggle_takes_back_a_queued_command() {
+        let jobs = Jobs::default();
+        jobs.queue("KEY_A", 1);
+        assert!(jobs.is_queued("KEY_A"));
+        assert!(jobs.cancel_queued("KEY_A"));
+        assert!(!jobs.cancel_queued("KEY_A"));
+        jobs.queue("KEY_A", 3);
+        assert!(!jobs.dequeue("KEY_A", 1));
+        assert!(jobs.dequeue("KEY_A", 3));
+        assert!(!jobs.is_queued("KEY_A"));
+        // Commands that started without waiting were never queued.
+        assert!(jobs.dequeue("KEY_A", 4));
+    }
+}
@@ -0,0 +1,206 @@
+//! Caps on how many commands run at once and how often one hotkey may start one.
+
+use clap::ValueEnum;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// Commands waiting to start, past this they are dropped even with `--overflow queue`.
+const MAX_QUEUED: usize = 64;
+
+// A queued command, started with its slot once it has one.
+type Start = Box<dyn FnOnce(Permit) + Send>;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Overflow {
+    /// Start the command once the limits allow it, in the order they came in
+    Queue,
+    /// Don't run the command
+    Drop,
+}
+
+pub struct Limits {
+    /// 0 for no limit, like `rate`.
+    max_running: usize,
+    /// Commands per hotkey per second.
+    rate: usize,
+    overflow: Overflow,
+    state: Mutex<State>,
+    changed: Condvar,
+    // Feeds the thread that starts queued commands, spawned when the first one is queued.
+    queue: OnceLock<Sender<(String, Start)>>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    started: HashMap<String, VecDeque<Instant>>,
+    queued: usize,
+}
+
+/// A running command's slot, freed on drop.
+pub struct Permit(Arc<Limits>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.lock().running -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Limits {
+    pub fn new(max_running: usize, rate: usize, overflow: Overflow) -> Self {
+        Limits {
+            max_running,
+            rate,
+            overflow,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+            queue: OnceLock::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Why `trigger` can't start a command now and, for the rate limit, when it can.
+    fn blocked(
+        &self,
+        state: &mut State,
+        trigger: &str,
+    ) -> Option<(&'static str, Option<Duration>)> {
+        if self.max_running > 0 && state.running >= self.max_running {
+            return Some(("too many commands running", None));
+        }
+        // Macro `exec` steps have no trigger and only count towards `max_running`.
+        if self.rate == 0 || trigger.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        let started = state.started.entry(trigger.to_string()).or_default();
+        while started.front().is_some_and(|t| now.duration_since(*t) >= RATE_WINDOW) {
+            started.pop_front();
+        }
+        match started.front() {
+            Some(oldest) if started.len() >= self.rate => {
+                Some(("rate limited", Some(RATE_WINDOW - now.duration_since(*oldest))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Starts a command of `trigger` through `start`, right away on this thread when the limits
+    /// allow it and nothing is queued before it. Otherwise, depending on the overflow mode, it is
+    /// queued behind the others, calling `on_queue` with the reason first, or fails with the
+    /// reason. Queued commands start in order on a thread of their own, at most `MAX_QUEUED`
+    /// wait at a time.
+    pub fn run(
+        self: &Arc<Self>,
+        trigger: &str,
+        on_queue: impl FnOnce(&str),
+        start: impl FnOnce(Permit) + Send + 'static,
+    ) -> Result<(), &'static str> {
+        let mut state = self.lock();
+        let reason = match self.blocked(&mut state, trigger) {
+            None if state.queued == 0 => {
+                let permit = self.take(&mut state, trigger);
+                drop(state);
+                start(permit);
+                return Ok(());
+            }
+            None => "waiting for queued commands",
+            Some((reason, _)) => reason,
+        };
+        if self.overflow == Overflow::Drop {
+            return Err(reason);
+        }
+        if state.queued >= MAX_QUEUED {
+            return Err("queue full");
+        }
+        state.queued += 1;
+        drop(state);
+        on_queue(reason);
+        let queue = self.queue.get_or_init(|| {
+            let (tx, rx) = mpsc::channel::<(String, Start)>();
+            let limits = self.clone();
+            thread::spawn(move || {
+                for (trigger, start) in rx {
+                    start(limits.acquire(&trigger));
+                }
+            });
+            tx
+        });
+        let _ = queue.send((trigger.to_string(), Box::new(start)));
+        Ok(())
+    }
+
+    // Waits for a slot for the queued command at the head of the queue.
+    fn acquire(self: &Arc<Self>, trigger: &str) -> Permit {
+        let mut state = self.lock();
+        while let Some((_, retry)) = self.blocked(&mut state, trigger) {
+            state = match retry {
+                Some(retry) => {
+                    self.changed.wait_timeout(state, retry).unwrap_or_else(|e| e.into_inner()).0
+                }
+                None => self.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+        state.queued -= 1;
+        self.take(&mut state, trigger)
+    }
+
+    fn take(self: &Arc<Self>, state: &mut State, trigger: &str) -> Permit {
+        state.running += 1;
+        if self.rate > 0 && !trigger.is_empty() {
+            state.started.entry(trigger.to_string()).or_default().push_back(Instant::now());
+        }
+        Permit(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands the permit of a started command back to the test.
+    fn run(limits: &Arc<Limits>, trigger: &str) -> Result<mpsc::Receiver<Permit>, &'static str> {
+        let (tx, rx) = mpsc::channel();
+        limits.run(trigger, |_| {}, move |permit| tx.send(permit).unwrap())?;
+        Ok(rx)
+    }
+
+    #[test]
+    fn overflow_is_dropped_until_a_slot_frees() {
+        let limits = Arc::new(Limits::new(1, 2, Overflow::Drop));
+        let first = run(&limits, "KEY_A").unwrap().recv().unwrap();
+        assert_eq!(run(&limits, "KEY_B").err(), Some("too many commands running"));
+        drop(first);
+        drop(run(&limits, "KEY_A").unwrap().recv().unwrap());
+        assert_eq!(run(&limits, "KEY_A").err(), Some("rate limited"));
+        assert!(run(&limits, "").is_ok());
+    }
+
+    #[test]
+    fn queued_commands_start_in_order_without_blocking() {
+        let limits = Arc::new(Limits::new(1, 0, Overflow::Queue));
+        let first = run(&limits, "KEY_A").unwrap().recv().unwrap();
+        let second = run(&limits, "KEY_B").unwrap();
+        let third = run(&limits, "KEY_C").unwrap();
+        assert!(second.try_recv().is_err());
+        drop(first);
+        drop(second.recv().unwrap());
+        drop(third.recv().unwrap());
+    }
+
+    #[test]
+    fn queue_is_capped() {
+        let limits = Arc::new(Limits::new(1, 0, Overflow::Queue));
+        let _first = run(&limits, "KEY_A").unwrap().recv().unwrap();
+        let _queued: Vec<_> = (0..MAX_QUEUED).map(|_| run(&limits, "KEY_B").unwrap()).collect();
+        assert_eq!(run(&limits, "KEY_C").err(), Some("queue full"));
+    }
+}
@@ -1,14 +1,14 @@
 use clap::Parser;
 use environ::Env;
 use nix::sys::stat::{Mode, umask};
-use std::io::Read;
-use std::time::{SystemTime, UNIX_EPOCH};
+use nix::unistd::Pid;
+use std::io::{ErrorKind, Write};
+use std::sync::{Arc, Mutex, mpsc};
 use std::{
     env,
     env::VarError,
     fs,
-    fs::OpenOptions,
-    os::unix::net::UnixListener,
+    os::unix::net::{UnixListener, UnixStream},
     os::unix::process::CommandExt,
     path::{Path, PathBuf},
     process::{Command, Stdio, exit, id},
@@ -16,30 +16,87 @@ use std::{
 };
 use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, RefreshKind, System, UpdateKind};
 
+mod auth;
 mod environ;
+mod failure;
+mod ipc;
+mod jobs;
+mod limits;
+mod output;
+mod systemd;
 
 /// IPC Server for swhkdp
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Set a custom log file. (Defaults to ${XDG_DATA_HOME:-$HOME/.local/share}/swhks-current_unix_time.log)
-    #[arg(short, long, value_name = "FILE")]
+    /// Set a custom log file. (Defaults to ${XDG_DATA_HOME:-$HOME/.local/share}/swhks/swhks.log)
+    #[arg(short, long, value_name = "FILE", conflicts_with = "log_stderr")]
     log: Option<PathBuf>,
 
+    /// Write command output to stderr with syslog priority prefixes, for journald
+    #[arg(long)]
+    log_stderr: bool,
+
+    /// How command output is logged
+    #[arg(long, value_enum, default_value_t = output::Format::Plain)]
+    log_format: output::Format,
+
+    /// Rotate the log file once it reaches this size, 0 disables rotation
+    #[arg(long, value_name = "BYTES", default_value_t = 10 << 20)]
+    log_max_size: u64,
+
+    /// Number of rotated log files to keep
+    #[arg(long, value_name = "COUNT", default_value_t = 3)]
+    log_keep: u32,
+
     /// Enable Debug Mode
     #[arg(short, long)]
     debug: bool,
+
+    /// The swhkdp binary allowed to connect. (Defaults to the swhkdp next to swhks)
+    #[arg(long, value_name = "FILE")]
+    swhkdp: Option<PathBuf>,
+
+    /// Also require swhkdp to present a secret written to $XDG_RUNTIME_DIR/swhks.secret
+    #[arg(long)]
+    secret: bool,
+
+    /// Shell command to run when a command fails, with $SWHKS_COMMAND, $SWHKS_EXIT_CODE,
+    /// $SWHKS_SIGNAL and $SWHKS_STDERR describing the failure. (Defaults to logging it only)
+    #[arg(long, value_name = "COMMAND")]
+    on_failure: Option<String>,
+
+    /// Most commands running at once, 0 for no limit
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    max_running: usize,
+
+    /// Most commands a single hotkey may start per second, 0 for no limit
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    rate_limit: usize,
+
+    /// What happens to a command that is over either limit
+    #[arg(long, value_enum, default_value_t = limits::Overflow::Queue)]
+    overflow: limits::Overflow,
 }
 
 fn main() -> std::io::Result<()> {
     let args = Args::parse();
-    if args.debug {
-        env_logger::Builder::from_env(env_logger::Env::default().filter_or("RUST_LOG", "debug"))
-            .init();
-    } else {
-        env_logger::Builder::from_env(env_logger::Env::default().filter_or("RUST_LOG", "warn"))
-            .init();
+    let level = if args.debug { "debug" } else { "warn" };
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().filter_or("RUST_LOG", level));
+    if args.log_stderr {
+        // journald takes the priority from the `<N>` prefix and adds its own timestamp.
+        logger.format(|buf, record| {
+            let priority = match record.level() {
+                log::Level::Error => 3,
+                log::Level::Warn => 4,
+                log::Level::Info => 6,
+                log::Level::Debug | log::Level::Trace => 7,
+            };
+            writeln!(buf, "<{priority}>{}", record.args())
+        });
     }
+    logger.init();
 
     log::debug!("Setting process umask.");
     umask(Mode::S_IWGRP | Mode::S_IWOTH);
@@ -64,31 +121,85 @@ fn main() -> std::io::Result<()> {
 
     let (pid_file_path, sock_file_path) = get_file_paths(&environ);
 
-    let log_file_name = if let Some(val) = args.log {
-        val
-    } else {
-        let time = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs().to_string(),
-            Err(_) => {
-                log::error!("SystemTime before UnixEpoch!");
-                exit(1);
-            }
-        };
-
-        format!("{}/swhks/swhks-{}.log", environ.data_home.to_string_lossy(), time).into()
+    let log_path = match args.log {
+        _ if args.log_stderr => None,
+        Some(path) => Some(path),
+        None => Some(environ.data_home.join("swhks/swhks.log")),
     };
-
-    let log_path = Path::new(&log_file_name);
-    if let Some(p) = log_path.parent()
+    if let Some(p) = log_path.as_deref().and_then(Path::parent)
         && !p.exists()
         && let Err(e) = fs::create_dir_all(p)
     {
         log::error!("Failed to create log dir: {e}");
     }
 
-    if Path::new(&pid_file_path).exists() {
+    // Under socket activation systemd owns the socket and keeps track of the service, the PID
+    // file only guards against starting swhks twice by hand.
+    let listener = match systemd::activated_listener() {
+        Some(listener) => {
+            log::info!("Using the socket passed by systemd");
+            listener
+        }
+        None => {
+            claim_instance(&pid_file_path, &sock_file_path);
+            UnixListener::bind(&sock_file_path)?
+        }
+    };
+
+    let Some(swhkdp_path) = args.swhkdp.or_else(auth::default_swhkdp_path) else {
+        log::error!("Can't tell where swhkdp is installed, pass it with --swhkdp");
+        exit(1);
+    };
+    let secret_path = environ.runtime_dir.join("swhks.secret");
+    let secret = if args.secret {
+        match auth::create_secret(&secret_path) {
+            Ok(secret) => Some(secret),
+            Err(e) => {
+                log::error!("Unable to write {}: {e}", secret_path.display());
+                exit(1);
+            }
+        }
+    } else {
+        // A leftover from an earlier run would make swhkdp present a secret nobody checks.
+        let _ = fs::remove_file(&secret_path);
+        None
+    };
+
+    let server = Arc::new(Server {
+        output: Arc::new(output::Output::new(
+            log_path,
+            args.log_format,
+            args.log_max_size,
+            args.log_keep,
+        )),
+        jobs: jobs::Jobs::default(),
+        limits: Arc::new(limits::Limits::new(args.max_running, args.rate_limit, args.overflow)),
+        environ,
+        secret,
+        on_failure: args.on_failure,
+    });
+    systemd::notify("READY=1");
+    loop {
+        match listener.accept() {
+            Ok((socket, address)) => {
+                log::debug!("Socket: {socket:?} Address: {address:?}");
+                if let Err(reason) = auth::check_peer(&socket, &swhkdp_path) {
+                    log::warn!("Rejected connection: {reason}");
+                    continue;
+                }
+                let server = server.clone();
+                thread::spawn(move || serve(socket, &server));
+            }
+            Err(e) => log::error!("accept function failed: {e:?}"),
+        }
+    }
+}
+
+// Exits if another swhks is running, otherwise takes over the socket path and the PID file.
+fn claim_instance(pid_file_path: &str, sock_file_path: &str) {
+    if Path::new(pid_file_path).exists() {
         log::debug!("Reading {pid_file_path} file and checking for running instances.");
-        let swhks_pid = match fs::read_to_string(&pid_file_path) {
+        let swhks_pid = match fs::read_to_string(pid_file_path) {
             Ok(swhks_pid) => swhks_pid,
             Err(e) => {
                 log::error!("Unable to read {e} to check all running instances");
@@ -114,9 +225,9 @@ fn main() -> std::io::Result<()> {
         }
     }
 
-    if Path::new(&sock_file_path).exists() {
+    if Path::new(sock_file_path).exists() {
         log::debug!("Sockfile exists, attempting to remove it.");
-        match fs::remove_file(&sock_file_path) {
+        match fs::remove_file(sock_file_path) {
             Ok(_) => {
                 log::debug!("Removed old socket file");
             }
@@ -128,28 +239,80 @@ fn main() -> std::io::Result<()> {
         };
     }
 
-    match fs::write(&pid_file_path, id().to_string()) {
+    match fs::write(pid_file_path, id().to_string()) {
         Ok(_) => {}
         Err(e) => {
             log::error!("Unable to write to {pid_file_path}: {e}");
             exit(1);
         }
     }
+}
+
+/// State shared by all swhkdp connections.
+struct Server {
+    output: Arc<output::Output>,
+    jobs: jobs::Jobs,
+    limits: Arc<limits::Limits>,
+    environ: Env,
+    secret: Option<String>,
+    on_failure: Option<String>,
+}
 
-    let listener = UnixListener::bind(sock_file_path)?;
+// Runs every command sent over one swhkdp connection until it closes.
+fn serve(mut socket: UnixStream, server: &Arc<Server>) {
+    let replies = match socket.try_clone() {
+        Ok(writer) => Arc::new(Mutex::new(writer)),
+        Err(e) => {
+            log::error!("Failed to set up swhkdp connection: {e}");
+            return;
+        }
+    };
+    let mut authenticated = server.secret.is_none();
     loop {
-        match listener.accept() {
-            Ok((mut socket, address)) => {
-                let mut response = String::new();
-                socket.read_to_string(&mut response)?;
-                run_system_command(&response, log_path);
-                log::debug!("Socket: {socket:?} Address: {address:?} Response: {response}");
+        let payload = match ipc::read_frame(&mut socket) {
+            Ok(payload) => payload,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                log::error!("Failed to read from swhkdp: {e}");
+                break;
+            }
+        };
+        match ipc::decode_request(&payload) {
+            Ok(ipc::Request::Hello { secret: presented }) => {
+                if let Some(secret) = &server.secret {
+                    if !auth::secrets_match(secret, &presented) {
+                        log::warn!("Rejected connection: wrong secret");
+                        reply(&replies, &ipc::encode_rejected("wrong secret"));
+                        break;
+                    }
+                    authenticated = true;
+                }
+            }
+            Ok(ipc::Request::Run(_)) if !authenticated => {
+                log::warn!("Rejected connection: no secret presented");
+                reply(&replies, &ipc::encode_rejected("no secret presented"));
+                break;
+            }
+            Ok(ipc::Request::Run(run)) => {
+                log::debug!("Command {} from {:?}: {}", run.id, run.trigger, run.command);
+                run_system_command(run, server, &replies);
+            }
+            Err(e) => {
+                log::error!("Dropping swhkdp connection after a bad request: {e}");
+                break;
             }
-            Err(e) => log::error!("accept function failed: {e:?}"),
         }
     }
 }
 
+// swhkdp may be gone by the time a command finishes, that's not worth more than a debug line.
+fn reply(replies: &Mutex<UnixStream>, frame: &[u8]) {
+    let mut writer = replies.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = writer.write_all(frame) {
+        log::debug!("Failed to reply to swhkdp: {e}");
+    }
+}
+
 fn get_file_paths(env: &Env) -> (String, String) {
     let pid_file_path = format!("{}/swhks.pid", env.runtime_dir.to_string_lossy());
     let sock_file_path = format!("{}/swhkdp.sock", env.runtime_dir.to_string_lossy());
@@ -157,39 +320,125 @@ fn get_file_paths(env: &Env) -> (String, String) {
     (pid_file_path, sock_file_path)
 }
 
-fn run_system_command(command: &str, log_path: &Path) {
-    let (stdout, stderr) = match OpenOptions::new().append(true).create(true).open(log_path) {
-        Ok(file) => match file.try_clone() {
-            Ok(clone) => (Stdio::from(file), Stdio::from(clone)),
-            Err(e) => {
-                _ = Command::new("notify-send").arg(format!("ERROR {e}")).spawn();
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), env::var_os("HOME")) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            PathBuf::from(home).join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
 
-                (Stdio::null(), Stdio::null())
+fn run_system_command(run: ipc::Run, server: &Arc<Server>, replies: &Arc<Mutex<UnixStream>>) {
+    // Macro `exec` steps have no trigger and always start a new command.
+    let tracked = !run.trigger.is_empty() && run.instance != ipc::Instance::Multiple;
+    if tracked {
+        let jobs = &server.jobs;
+        match run.instance {
+            ipc::Instance::Single
+                if jobs.is_running(&run.trigger) || jobs.is_queued(&run.trigger) =>
+            {
+                log::info!("{:?} is still running, not starting {}", run.trigger, run.command);
+                reply(replies, &ipc::encode_skipped(run.id, "already running"));
+                return;
             }
-        },
-        Err(e) => {
-            _ = Command::new("notify-send").arg(format!("ERROR {e}")).spawn();
-
-            (Stdio::null(), Stdio::null())
+            ipc::Instance::Toggle
+                if jobs.stop(&run.trigger) || jobs.cancel_queued(&run.trigger) =>
+            {
+                log::info!("Stopped the running instance of {:?}", run.trigger);
+                reply(replies, &ipc::encode_skipped(run.id, "stopped the running instance"));
+                return;
+            }
+            _ => {}
+        }
+    }
+    let (id, command) = (run.id, run.command.clone());
+    let trigger = run.trigger.clone();
+    let on_queue = |reason: &str| {
+        log::info!("Queued {command}: {reason}");
+        if tracked {
+            server.jobs.queue(&trigger, id);
         }
+        reply(replies, &ipc::encode_queued(id, reason));
     };
-    match Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .stdin(Stdio::null())
-        .stdout(stdout)
-        .stderr(stderr)
-        .process_group(0)
-        .spawn()
-    {
+    let start = {
+        let (server, replies) = (server.clone(), replies.clone());
+        move |permit| {
+            // A later `toggle` press may have taken it back while it was queued.
+            if tracked && !server.jobs.dequeue(&run.trigger, run.id) {
+                log::info!("Not starting {}, stopped while queued", run.command);
+                reply(&replies, &ipc::encode_skipped(run.id, "stopped while queued"));
+                return;
+            }
+            start_command(run, permit, &server, &replies);
+        }
+    };
+    if let Err(reason) = server.limits.run(&trigger, on_queue, start) {
+        log::info!("Dropped {command}: {reason}");
+        reply(replies, &ipc::encode_skipped(id, reason));
+    }
+}
+
+// Spawns the command, `permit` holds its slot until it exits.
+fn start_command(
+    run: ipc::Run,
+    permit: limits::Permit,
+    server: &Arc<Server>,
+    replies: &Arc<Mutex<UnixStream>>,
+) {
+    let (stdout, stderr) = server.output.stdio();
+    let (program, args) = match run.shell.split_first() {
+        Some((program, args)) => (program.as_str(), args),
+        None => ("sh", &["-c".to_string()][..]),
+    };
+    let mut command = Command::new(program);
+    command.args(args).arg(&run.command).envs(server.environ.session_vars()).envs(run.env);
+    if let Some(cwd) = &run.cwd {
+        command.current_dir(expand_home(cwd));
+    }
+    match command.stdin(Stdio::null()).stdout(stdout).stderr(stderr).process_group(0).spawn() {
         Ok(mut child) => {
+            reply(replies, &ipc::encode_spawned(run.id, None));
+            let trigger = if run.trigger.is_empty() { "exec" } else { &run.trigger };
+            let label = format!("{trigger}[{}]", run.id);
+            server.output.event(&label, &format!("started {}", run.command), false);
+            let captured = server.output.capture(&mut child, &label);
+            let pgid = Pid::from_raw(child.id() as i32);
+            if !run.trigger.is_empty() && run.instance != ipc::Instance::Multiple {
+                server.jobs.insert(&run.trigger, pgid);
+            }
+            let (done_tx, done_rx) = mpsc::channel();
+            if let Some(timeout) = run.timeout {
+                let command = run.command.clone();
+                thread::spawn(move || jobs::enforce_timeout(pgid, timeout, done_rx, &command));
+            }
+            let replies = replies.clone();
+            let server = server.clone();
             thread::spawn(move || {
-                let _ = child.wait();
+                let status = child.wait();
+                drop(permit);
+                let _ = done_tx.send(());
+                match status {
+                    Ok(status) => {
+                        reply(&replies, &ipc::encode_exited(run.id, status));
+                        // After the command's last line, which may come from background children
+                        // still holding its output.
+                        let stderr_tail = captured.finish();
+                        let stopped = server.jobs.was_stopped(pgid);
+                        let failed = !status.success() && !stopped;
+                        server.output.event(&label, &format!("exited with {status}"), failed);
+                        if failed {
+                            failure::report(&server, &run.command, status, &stderr_tail);
+                        }
+                    }
+                    Err(e) => log::error!("Failed to wait for {}: {e}", run.command),
+                }
             });
         }
         Err(e) => {
-            log::error!("Failed to execute {command}");
+            log::error!("Failed to execute {}", run.command);
             log::error!("Error: {e}");
+            reply(replies, &ipc::encode_spawned(run.id, Some(&e.to_string())));
         }
     }
 }
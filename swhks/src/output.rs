@@ -0,0 +1,270 @@
+//! Where command output goes: a log file rotated by size, or stderr for journald.
+
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Lines of stderr kept for the failure report.
+const STDERR_TAIL_LINES: usize = 20;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Command output as the command wrote it
+    Plain,
+    /// Every line prefixed with time, hotkey and command id, plus a line when it exits
+    Structured,
+}
+
+pub struct Output {
+    /// `None` sends everything to stderr.
+    path: Option<PathBuf>,
+    format: Format,
+    max_size: u64,
+    keep: u32,
+    file: Mutex<Option<LogFile>>,
+}
+
+// The log file `write_line` appends to.
+struct LogFile {
+    file: File,
+    // Its size when opened plus what went through this handle, commands writing plain output
+    // straight to the file aren't counted. Only once this reaches `max_size` is the file checked.
+    size: u64,
+}
+
+impl Output {
+    pub fn new(path: Option<PathBuf>, format: Format, max_size: u64, keep: u32) -> Self {
+        Output { path, format, max_size, keep, file: Mutex::new(None) }
+    }
+
+    // `swhks.log` becomes `swhks.log.1`, `.1` becomes `.2` and so on, `keep` files are kept.
+    fn rotate(&self, path: &Path) -> io::Result<bool> {
+        let size = match fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if self.max_size == 0 || size < self.max_size {
+            return Ok(false);
+        }
+        let numbered = |n: u32| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        match fs::remove_file(numbered(self.keep.max(1))) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for n in (1..self.keep).rev() {
+            match fs::rename(numbered(n), numbered(n + 1)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            fs::rename(path, numbered(1))?;
+        }
+        Ok(true)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<File> {
+        match self.rotate(path) {
+            // `write_line` would keep appending to the rotated file.
+            Ok(true) => *self.file.lock().unwrap_or_else(|e| e.into_inner()) = None,
+            Ok(false) => {}
+            Err(e) => log::warn!("Failed to rotate {}: {e}", path.display()),
+        }
+        OpenOptions::new().append(true).create(true).open(path)
+    }
+
+    /// Stdout and stderr for a command about to start. Stderr, and stdout of structured output,
+    /// is read back through a pipe by [`Output::capture`], plain stdout goes straight to the file
+    /// or stderr.
+    pub fn stdio(&self) -> (Stdio, Stdio) {
+        if self.format == Format::Structured {
+            return (Stdio::piped(), Stdio::piped());
+        }
+        let file = match &self.path {
+            Some(path) => self.open(path),
+            None => io::stderr().as_fd().try_clone_to_owned().map(File::from),
+        };
+        match file {
+            Ok(stdout) => (Stdio::from(stdout), Stdio::piped()),
+            Err(e) => {
+                log::error!("Can't log command output: {e}");
+                (Stdio::null(), Stdio::piped())
+            }
+        }
+    }
+
+    /// Forwards the piped output of `child` line by line, see [`Output::stdio`].
+    pub fn capture(self: &Arc<Self>, child: &mut Child, label: &str) -> Captured {
+        Captured {
+            stdout: child.stdout.take().map(|p| self.forward(p, format!("{label} stdout"), 6)),
+            stderr: child.stderr.take().map(|p| self.forward(p, format!("{label} stderr"), 4)),
+        }
+    }
+
+    fn forward(
+        self: &Arc<Self>,
+        pipe: impl Read + Send + 'static,
+        label: String,
+        priority: u8,
+    ) -> JoinHandle<VecDeque<String>> {
+        let output = self.clone();
+        thread::spawn(move || {
+            let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+            for line in BufReader::new(pipe).lines() {
+                match line {
+                    Ok(line) => {
+                        output.write_line(&label, &line, priority);
+                        if tail.len() == STDERR_TAIL_LINES {
+                            tail.pop_front();
+                        }
+                        tail.push_back(line);
+                    }
+                    Err(e) if e.kind() == ErrorKind::InvalidData => continue,
+                    Err(_) => break,
+                }
+            }
+            tail
+        })
+    }
+
+    /// Records something that happened to a command, only in structured output.
+    pub fn event(&self, label: &str, text: &str, failed: bool) {
+        if self.format == Format::Structured {
+            self.write_line(label, text, if failed { 4 } else { 6 });
+        }
+    }
+
+    // Structured lines on stderr carry a syslog priority prefix instead of a timestamp, journald
+    // adds its own timestamp and understands the prefix.
+    fn write_line(&self, label: &str, text: &str, priority: u8) {
+        let Some(path) = &self.path else {
+            match self.format {
+                Format::Plain => eprintln!("{text}"),
+                Format::Structured => eprintln!("<{priority}>{label}: {text}"),
+            }
+            return;
+        };
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if self.max_size > 0 && file.as_ref().is_some_and(|f| f.size >= self.max_size) {
+            // Reopened either way, the size counted so far may be off.
+            if let Err(e) = self.rotate(path) {
+                log::warn!("Failed to rotate {}: {e}", path.display());
+            }
+            *file = None;
+        }
+        if file.is_none() {
+            match OpenOptions::new().append(true).create(true).open(path) {
+                Ok(opened) => {
+                    let size = opened.metadata().map_or(0, |m| m.len());
+                    *file = Some(LogFile { file: opened, size });
+                }
+                Err(e) => {
+                    log::error!("Failed to open {}: {e}", path.display());
+                    return;
+                }
+            }
+        }
+        let line = match self.format {
+            Format::Plain => format!("{text}\n"),
+            Format::Structured => format!("{} {label}: {text}\n", timestamp()),
+        };
+        if let Some(f) = file.as_mut() {
+            match f.file.write_all(line.as_bytes()) {
+                Ok(()) => f.size += line.len() as u64,
+                Err(e) => {
+                    log::error!("Failed to write to {}: {e}", path.display());
+                    *file = None;
+                }
+            }
+        }
+    }
+}
+
+/// Reader threads of one command's output.
+pub struct Captured {
+    stdout: Option<JoinHandle<VecDeque<String>>>,
+    stderr: Option<JoinHandle<VecDeque<String>>>,
+}
+
+impl Captured {
+    /// Waits until the command's output is closed, which includes any background children it
+    /// left holding it, and returns the last lines of stderr.
+    pub fn finish(self) -> Vec<String> {
+        if let Some(stdout) = self.stdout {
+            let _ = stdout.join();
+        }
+        self.stderr.and_then(|stderr| stderr.join().ok()).map(Vec::from).unwrap_or_default()
+    }
+}
+
+// RFC 3339 in UTC, e.g. `2024-05-01T12:34:56Z`.
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_logs_are_shifted_and_pruned() {
+        let dir = std::env::temp_dir().join(format!("swhks-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("swhks.log");
+        let output = Output::new(Some(path.clone()), Format::Plain, 4, 2);
+        for (name, content) in [("swhks.log", "3333"), ("swhks.log.1", "2"), ("swhks.log.2", "1")] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        assert!(output.rotate(&path).unwrap());
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(dir.join("swhks.log.1")).unwrap(), "3333");
+        assert_eq!(fs::read_to_string(dir.join("swhks.log.2")).unwrap(), "2");
+        assert!(!dir.join("swhks.log.3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lines_are_rotated_once_the_limit_is_written() {
+        let dir = std::env::temp_dir().join(format!("swhks-write-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("swhks.log");
+        let output = Output::new(Some(path.clone()), Format::Plain, 8, 1);
+        for text in ["abc", "def", "ghi"] {
+            output.write_line("", text, 6);
+        }
+        assert_eq!(fs::read_to_string(dir.join("swhks.log.1")).unwrap(), "abc\ndef\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ghi\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
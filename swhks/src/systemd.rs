@@ -0,0 +1,63 @@
+//! Socket activation and readiness notification when swhks runs as a systemd user service.
+
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use nix::unistd::getpid;
+use std::env;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+
+// First descriptor passed by the service manager, see sd_listen_fds(3).
+const LISTEN_FDS_START: i32 = 3;
+
+// Keeps commands from inheriting variables meant for swhks, like `sd_listen_fds(1)`.
+fn unset(names: &[&str]) {
+    for name in names {
+        // SAFETY: only called during startup, before swhks spawns any thread.
+        unsafe { env::remove_var(name) };
+    }
+}
+
+/// The listening socket systemd passed to this process, if it was socket activated.
+/// Must be called before any thread is spawned.
+pub fn activated_listener() -> Option<UnixListener> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    unset(&["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"]);
+    let pid = pid?.parse::<i32>().ok()?;
+    // The variables are inherited, only the process they were set for may use them.
+    if pid != getpid().as_raw() {
+        return None;
+    }
+    let fds = fds?.parse::<i32>().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        log::warn!("Got {fds} sockets from systemd, only using the first one");
+    }
+    // SAFETY: with LISTEN_PID naming us, systemd handed this descriptor over and nothing else
+    // in this process has claimed it.
+    let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+    // Commands must not inherit the listening socket.
+    if let Err(e) = fcntl(&fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        log::warn!("Failed to set FD_CLOEXEC on the activated socket: {e}");
+    }
+    Some(UnixListener::from(fd))
+}
+
+/// Sends `state`, e.g. `READY=1`, to the service manager. Does nothing outside a
+/// `Type=notify` service. Like `sd_notify(1, …)` it unsets `NOTIFY_SOCKET`, so it only works
+/// once, and must be called before any thread is spawned.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else { return };
+    unset(&["NOTIFY_SOCKET"]);
+    let addr = match path.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let sent = addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+    if let Err(e) = sent {
+        log::warn!("Failed to notify systemd ({state}): {e}");
+    }
+}